@@ -0,0 +1,16 @@
+//! Runs arbitrary output through the whole emulator, checking that the cursor stays on the screen
+//! and the formatting stays consistent after every read
+
+#![no_main]
+
+use cmlterm::terminal_emulator::{fuzz, EmulatorCore};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let mut emulator = EmulatorCore::new(80, 24);
+    fuzz::check_emulator(&mut emulator, data);
+
+    // Small screens hit the edges far more often
+    let mut emulator = EmulatorCore::new(7, 3);
+    fuzz::check_emulator(&mut emulator, data);
+});
@@ -0,0 +1,11 @@
+//! Parses arbitrary output, which must not panic and must give the same result however it is
+//! split across reads
+
+#![no_main]
+
+use cmlterm::terminal_emulator::fuzz;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    fuzz::check_chunked_parse(data);
+});
@@ -0,0 +1,219 @@
+use std::{path::PathBuf, time::Duration};
+
+use cmlterm::terminal_emulator::SessionConfig;
+
+use crate::{config::apply_config_file, gui::WindowConfig, logging::LogConfig};
+
+pub const USAGE: &str = "\
+Usage: cmlterm [OPTIONS] [-e PROGRAM [ARGS...]]
+
+Options:
+  -e, --command PROGRAM [ARGS...]  Run PROGRAM instead of the shell, takes all remaining arguments
+      --working-directory DIR      Start the program in DIR
+      --title TITLE                Window title
+      --class CLASS                Window class, used by window managers to match the window
+      --config FILE                Read settings from a TOML config file
+      --hold                       Keep the window open after the program exits
+      --cursor-blink-interval MS   Time the cursor spends on and off when blinking, 0 disables
+                                   blinking, defaults to 500
+      --no-hollow-cursor           Keep drawing a solid block cursor when the window is unfocused
+      --log FILTER                 Log levels, e.g. warn,parser=debug (targets: parser, emulator,
+                                   pty, gui, config), defaults to $CMLTERM_LOG or warn
+      --log-file FILE              Write the log to FILE instead of stderr
+  -h, --help                       Print this help
+  -V, --version                    Print the version";
+
+#[derive(Default)]
+pub struct Options {
+    pub session: SessionConfig,
+    pub window: WindowConfig,
+    pub log: LogConfig,
+    /// Problems that didn't stop parsing, logged once logging is set up
+    pub warnings: Vec<String>,
+}
+
+pub enum Action {
+    Run(Box<Options>),
+    Help,
+    Version,
+}
+
+/// Settings given on the command line, which take priority over the config file
+#[derive(Default)]
+struct Args {
+    config: Option<PathBuf>,
+    command: Option<Vec<String>>,
+    working_directory: Option<PathBuf>,
+    title: Option<String>,
+    class: Option<String>,
+    hold: bool,
+    cursor_blink_interval: Option<Duration>,
+    no_hollow_cursor: bool,
+    log: Option<String>,
+    log_file: Option<PathBuf>,
+}
+
+/// Parses the arguments after argv[0]
+pub fn parse_args<I: IntoIterator<Item = String>>(args: I) -> Result<Action, String> {
+    let mut parsed = Args::default();
+    let mut args = args.into_iter();
+
+    while let Some(arg) = args.next() {
+        // Options that take a value accept both --opt value and --opt=value
+        let (name, inline_val) = match arg.split_once('=') {
+            Some((name, val)) if name.starts_with("--") => {
+                (name.to_string(), Some(val.to_string()))
+            }
+            _ => (arg, None),
+        };
+
+        let mut value = || {
+            inline_val
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("{name} requires a value"))
+        };
+
+        match name.as_str() {
+            "--help" | "--version" | "--hold" | "--no-hollow-cursor" if inline_val.is_some() => {
+                return Err(format!("{name} doesn't take a value"));
+            }
+            "-h" | "--help" => return Ok(Action::Help),
+            "-V" | "--version" => return Ok(Action::Version),
+            "--hold" => parsed.hold = true,
+            "--no-hollow-cursor" => parsed.no_hollow_cursor = true,
+            "--cursor-blink-interval" => {
+                let ms = value()?
+                    .parse()
+                    .map_err(|_| format!("{name} should be a number of milliseconds"))?;
+                parsed.cursor_blink_interval = Some(Duration::from_millis(ms));
+            }
+            "--config" => parsed.config = Some(value()?.into()),
+            "--working-directory" => parsed.working_directory = Some(value()?.into()),
+            "--title" => parsed.title = Some(value()?),
+            "--class" => parsed.class = Some(value()?),
+            "--log" => parsed.log = Some(value()?),
+            "--log-file" => parsed.log_file = Some(value()?.into()),
+            "-e" | "--command" => {
+                let command = inline_val.into_iter().chain(args).collect::<Vec<_>>();
+                if command.is_empty() {
+                    return Err(format!("{name} requires a program"));
+                }
+                parsed.command = Some(command);
+                break;
+            }
+            _ => return Err(format!("unknown argument {name}")),
+        }
+    }
+
+    let mut options = Options::default();
+    if let Some(config) = &parsed.config {
+        apply_config_file(config, &mut options)?;
+    }
+
+    if let Some(mut command) = parsed.command {
+        options.session.program = command.remove(0).into();
+        options.session.args = command;
+    }
+    if let Some(working_directory) = parsed.working_directory {
+        options.session.cwd = Some(working_directory);
+    }
+    if let Some(title) = parsed.title {
+        options.window.title = title;
+    }
+    if let Some(class) = parsed.class {
+        options.window.class = Some(class);
+    }
+    options.window.hold |= parsed.hold;
+    if let Some(interval) = parsed.cursor_blink_interval {
+        options.window.cursor.blink_interval = interval;
+    }
+    if parsed.no_hollow_cursor {
+        options.window.cursor.hollow_when_unfocused = false;
+    }
+    if let Some(log) = parsed.log {
+        options.log.filter = Some(log);
+    }
+    if let Some(log_file) = parsed.log_file {
+        options.log.file = Some(log_file);
+    }
+
+    Ok(Action::Run(Box::new(options)))
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Action, String> {
+        parse_args(args.iter().map(|arg| arg.to_string()))
+    }
+
+    fn parse_options(args: &[&str]) -> Options {
+        match parse(args) {
+            Ok(Action::Run(options)) => *options,
+            _ => panic!("expected options for {args:?}"),
+        }
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_options(&[
+            "--title",
+            "hello",
+            "--class=scratch",
+            "--working-directory",
+            "/tmp",
+            "--hold",
+            "--cursor-blink-interval=250",
+            "--no-hollow-cursor",
+            "--log=parser=debug",
+            "--log-file",
+            "/tmp/cmlterm.log",
+            "-e",
+            "htop",
+            "--title",
+            "x",
+        ]);
+        assert_eq!(options.window.title, "hello");
+        assert_eq!(options.window.class.as_deref(), Some("scratch"));
+        assert!(options.window.hold);
+        assert_eq!(
+            options.window.cursor.blink_interval,
+            Duration::from_millis(250)
+        );
+        assert!(!options.window.cursor.hollow_when_unfocused);
+        assert_eq!(options.session.cwd.as_deref(), Some(Path::new("/tmp")));
+        assert_eq!(options.session.program, Path::new("htop"));
+        assert_eq!(options.session.args, ["--title", "x"]);
+        assert_eq!(options.log.filter.as_deref(), Some("parser=debug"));
+        assert_eq!(
+            options.log.file.as_deref(),
+            Some(Path::new("/tmp/cmlterm.log"))
+        );
+
+        let options = parse_options(&[]);
+        assert_eq!(options.window.title, "cmlterm");
+        assert!(!options.window.hold);
+        assert_eq!(
+            options.window.cursor.blink_interval,
+            Duration::from_millis(500)
+        );
+        assert!(options.window.cursor.hollow_when_unfocused);
+    }
+
+    #[test]
+    fn test_parse_args_actions() {
+        assert!(matches!(parse(&["--hold", "--help"]), Ok(Action::Help)));
+        assert!(matches!(parse(&["-V"]), Ok(Action::Version)));
+        assert!(parse(&["--title"]).is_err());
+        assert!(parse(&["-e"]).is_err());
+        assert!(parse(&["--cursor-blink-interval", "fast"]).is_err());
+        assert!(parse(&["--bogus"]).is_err());
+        assert!(parse(&["--hold=foo"]).is_err());
+        assert!(parse(&["--help="]).is_err());
+        assert!(parse(&["--config", "/nonexistent/cmlterm.toml"]).is_err());
+    }
+}
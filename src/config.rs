@@ -0,0 +1,153 @@
+use std::{path::Path, time::Duration};
+
+use toml_edit::{DocumentMut, Item};
+
+use crate::cli::Options;
+
+fn expect_str<'a>(key: &str, item: &'a Item) -> Result<&'a str, String> {
+    item.as_str()
+        .ok_or_else(|| format!("{key} should be a string"))
+}
+
+fn expect_bool(key: &str, item: &Item) -> Result<bool, String> {
+    item.as_bool()
+        .ok_or_else(|| format!("{key} should be a boolean"))
+}
+
+fn expect_millis(key: &str, item: &Item) -> Result<Duration, String> {
+    item.as_integer()
+        .and_then(|ms| u64::try_from(ms).ok())
+        .map(Duration::from_millis)
+        .ok_or_else(|| format!("{key} should be a number of milliseconds"))
+}
+
+fn expect_str_array(key: &str, item: &Item) -> Result<Vec<String>, String> {
+    let err = || format!("{key} should be an array of strings");
+    item.as_array()
+        .ok_or_else(err)?
+        .iter()
+        .map(|val| val.as_str().map(str::to_string).ok_or_else(err))
+        .collect()
+}
+
+/// Applies the settings in a TOML config file on top of options, e.g.
+///
+/// ```toml
+/// program = "/bin/zsh"
+/// args = ["-o", "vi"]
+/// login = true
+/// working_directory = "/home/me"
+/// title = "zsh"
+/// class = "cmlterm-zsh"
+/// hold = false
+/// confirm_paste = true
+/// cursor_blink_interval = 500
+/// cursor_hollow_when_unfocused = true
+/// log = "warn,parser=debug"
+/// log_file = "/tmp/cmlterm.log"
+/// env_remove = ["PROMPT_COMMAND"]
+///
+/// [env]
+/// EDITOR = "vim"
+/// ```
+pub fn apply_config_str(config: &str, options: &mut Options) -> Result<(), String> {
+    let doc = config
+        .parse::<DocumentMut>()
+        .map_err(|e| format!("invalid config: {e}"))?;
+
+    for (key, item) in doc.iter() {
+        match key {
+            "program" => options.session.program = expect_str(key, item)?.into(),
+            "args" => options.session.args = expect_str_array(key, item)?,
+            "login" => options.session.login = expect_bool(key, item)?,
+            "working_directory" => options.session.cwd = Some(expect_str(key, item)?.into()),
+            "env_remove" => options.session.env_remove = expect_str_array(key, item)?,
+            "env" => {
+                let table = item
+                    .as_table_like()
+                    .ok_or_else(|| "env should be a table".to_string())?;
+                for (name, val) in table.iter() {
+                    let val = expect_str(&format!("env.{name}"), val)?;
+                    options
+                        .session
+                        .env
+                        .push((name.to_string(), val.to_string()));
+                }
+            }
+            "title" => options.window.title = expect_str(key, item)?.to_string(),
+            "class" => options.window.class = Some(expect_str(key, item)?.to_string()),
+            "hold" => options.window.hold = expect_bool(key, item)?,
+            "confirm_paste" => options.window.confirm_paste = expect_bool(key, item)?,
+            "cursor_blink_interval" => {
+                options.window.cursor.blink_interval = expect_millis(key, item)?;
+            }
+            "cursor_hollow_when_unfocused" => {
+                options.window.cursor.hollow_when_unfocused = expect_bool(key, item)?;
+            }
+            "log" => options.log.filter = Some(expect_str(key, item)?.to_string()),
+            "log_file" => options.log.file = Some(expect_str(key, item)?.into()),
+            _ => options.warnings.push(format!("Unknown config key {key}")),
+        }
+    }
+
+    Ok(())
+}
+
+pub fn apply_config_file(path: &Path, options: &mut Options) -> Result<(), String> {
+    let config = std::fs::read_to_string(path)
+        .map_err(|e| format!("failed to read {}: {e}", path.display()))?;
+    apply_config_str(&config, options)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_apply_config() {
+        let mut options = Options::default();
+        apply_config_str(
+            r#"
+            program = "/bin/zsh"
+            args = ["-o", "vi"]
+            login = true
+            working_directory = "/tmp"
+            title = "zsh"
+            hold = true
+            confirm_paste = false
+            cursor_blink_interval = 0
+            cursor_hollow_when_unfocused = false
+            log = "info"
+
+            [env]
+            EDITOR = "vim"
+            "#,
+            &mut options,
+        )
+        .unwrap();
+
+        assert_eq!(options.session.program, Path::new("/bin/zsh"));
+        assert_eq!(options.session.args, ["-o", "vi"]);
+        assert!(options.session.login);
+        assert_eq!(options.session.cwd.as_deref(), Some(Path::new("/tmp")));
+        assert_eq!(
+            options.session.env,
+            [("EDITOR".to_string(), "vim".to_string())]
+        );
+        assert_eq!(options.window.title, "zsh");
+        assert!(options.window.hold);
+        assert!(!options.window.confirm_paste);
+        assert_eq!(options.window.cursor.blink_interval, Duration::ZERO);
+        assert!(!options.window.cursor.hollow_when_unfocused);
+        assert_eq!(options.log.filter.as_deref(), Some("info"));
+
+        assert!(options.warnings.is_empty());
+        apply_config_str("bogus = 1", &mut options).unwrap();
+        assert_eq!(options.warnings, ["Unknown config key bogus"]);
+
+        assert!(apply_config_str("hold = 1", &mut options).is_err());
+        assert!(apply_config_str("args = [1]", &mut options).is_err());
+        assert!(apply_config_str("cursor_blink_interval = -1", &mut options).is_err());
+        assert!(apply_config_str("title = ", &mut options).is_err());
+    }
+}
@@ -1,11 +1,153 @@
-use crate::terminal_emulator::{ CursorState, TerminalColor, TerminalEmulator };
-use eframe::egui::{ self, CentralPanel, Color32, Event, InputState, Key, Rect, TextStyle, Ui, FontData, FontFamily, FontDefinitions };
+use std::{
+    sync::{mpsc::Receiver, Arc, Mutex},
+    time::Duration,
+};
+
+use cmlterm::terminal_emulator::{
+    spawn_reader_thread, CursorShape, CursorState, CursorStyle, MouseButton, MouseEvent,
+    MouseEventKind, MouseModifiers, MouseTracking, Rgb, TerminalEmulator, TerminalError,
+    TerminalEvent, TerminalSnapshot,
+};
+use eframe::egui::{ self, CentralPanel, Color32, Event, Frame, InputState, Key, Modifiers, PointerButton, Pos2, Rect, TextStyle, Ui, FontData, FontFamily, FontDefinitions };
 
 const REGULAR_FONT_NAME: &str = "hack";
 const BOLD_FONT_NAME: &str = "hack-bold";
 
-fn write_input_to_terminal(input: &InputState, terminal_emulator: &mut TerminalEmulator) {
+/// Cell under the pointer. Positions off the grid, in the window padding or while dragging past
+/// the edge, report the nearest cell like xterm does
+fn pointer_to_cell(
+    pos: Pos2,
+    terminal_origin: Pos2,
+    character_size: &(f32, f32),
+    grid_size: (usize, usize),
+) -> (usize, usize) {
+    let x = ((pos.x - terminal_origin.x) / character_size.0).max(0.0) as usize;
+    let y = ((pos.y - terminal_origin.y) / character_size.1).max(0.0) as usize;
+    (
+        x.min(grid_size.0.saturating_sub(1)),
+        y.min(grid_size.1.saturating_sub(1)),
+    )
+}
+
+fn egui_button_to_mouse_button(button: PointerButton) -> Option<MouseButton> {
+    match button {
+        PointerButton::Primary => Some(MouseButton::Left),
+        PointerButton::Middle => Some(MouseButton::Middle),
+        PointerButton::Secondary => Some(MouseButton::Right),
+        PointerButton::Extra1 | PointerButton::Extra2 => None,
+    }
+}
+
+fn held_mouse_button(input: &InputState) -> Option<MouseButton> {
+    [
+        PointerButton::Primary,
+        PointerButton::Middle,
+        PointerButton::Secondary,
+    ]
+    .into_iter()
+    .find(|button| input.pointer.button_down(*button))
+    .and_then(egui_button_to_mouse_button)
+}
+
+fn egui_event_to_mouse_event(
+    event: &Event,
+    input: &InputState,
+    terminal_origin: Pos2,
+    character_size: &(f32, f32),
+    grid_size: (usize, usize),
+) -> Option<MouseEvent> {
+    let (kind, pos, modifiers) = match event {
+        Event::PointerButton {
+            pos,
+            button,
+            pressed,
+            modifiers,
+        } => {
+            let button = egui_button_to_mouse_button(*button)?;
+            let kind = if *pressed {
+                MouseEventKind::Press(button)
+            } else {
+                MouseEventKind::Release(button)
+            };
+            (kind, *pos, *modifiers)
+        }
+        Event::PointerMoved(pos) => (
+            MouseEventKind::Motion(held_mouse_button(input)),
+            *pos,
+            input.modifiers,
+        ),
+        Event::MouseWheel {
+            delta, modifiers, ..
+        } => {
+            let button = if delta.y > 0.0 {
+                MouseButton::WheelUp
+            } else if delta.y < 0.0 {
+                MouseButton::WheelDown
+            } else {
+                return None;
+            };
+            (
+                MouseEventKind::Press(button),
+                input.pointer.latest_pos()?,
+                *modifiers,
+            )
+        }
+        _ => return None,
+    };
+
+    // Shift is reserved for local selection, like in xterm
+    if modifiers.shift {
+        return None;
+    }
+
+    let (x, y) = pointer_to_cell(pos, terminal_origin, character_size, grid_size);
+    Some(MouseEvent {
+        kind,
+        x,
+        y,
+        modifiers: egui_modifiers_to_mouse_modifiers(modifiers),
+    })
+}
+
+fn egui_modifiers_to_mouse_modifiers(modifiers: Modifiers) -> MouseModifiers {
+    MouseModifiers {
+        shift: modifiers.shift,
+        alt: modifiers.alt,
+        ctrl: modifiers.ctrl,
+    }
+}
+
+fn write_input_to_terminal(
+    input: &InputState,
+    terminal_emulator: &mut TerminalEmulator,
+    terminal_origin: Option<Pos2>,
+    character_size: &(f32, f32),
+    paste_confirmation: &mut PasteConfirmation,
+) {
+    let mouse_tracking = terminal_emulator.core().mouse_tracking() != MouseTracking::None;
+    let screen = terminal_emulator.core().screen();
+    let grid_size = (screen.width(), screen.height());
     for event in &input.events {
+        if let (true, Some(terminal_origin)) = (mouse_tracking, terminal_origin) {
+            if let Some(mouse_event) =
+                egui_event_to_mouse_event(event, input, terminal_origin, character_size, grid_size)
+            {
+                if let Err(e) = terminal_emulator.mouse_event(mouse_event) {
+                    log::warn!(target: "gui", "{e}");
+                }
+                continue;
+            }
+        }
+
+        if let Event::Paste(text) = event {
+            if paste_confirmation.needs_confirmation(text, terminal_emulator) {
+                paste_confirmation.pending = Some(text.clone());
+            } else if let Err(e) = terminal_emulator.paste(text) {
+                log::warn!(target: "gui", "{e}");
+            }
+            continue;
+        }
+
         let text = match event {
             Event::Text(text) => text,
             Event::Key {
@@ -16,10 +158,16 @@ fn write_input_to_terminal(input: &InputState, terminal_emulator: &mut TerminalE
             _ => "",
         };
 
-        terminal_emulator.write(text.as_bytes());
+        if let Err(e) = terminal_emulator.write(text.as_bytes()) {
+            log::warn!(target: "gui", "{e}");
+        }
     }
 }
 
+fn rgb_to_color32(color: Rgb) -> Color32 {
+    Color32::from_rgb(color.r, color.g, color.b)
+}
+
 fn get_char_size(ctx: &egui::Context) -> (f32, f32) {
     let font_id = ctx.style().text_styles[&egui::TextStyle::Monospace].clone();
     ctx.fonts(move |fonts| {
@@ -52,11 +200,35 @@ fn character_to_cursor_offset(
     (x_offset, y_offset)
 }
 
+pub struct CursorConfig {
+    /// Time the cursor spends in each of the visible and hidden phases of a blink, zero turns
+    /// blinking off
+    pub blink_interval: Duration,
+    /// Draw block cursors as an outline when the window is not focused
+    pub hollow_when_unfocused: bool,
+}
+
+impl Default for CursorConfig {
+    fn default() -> CursorConfig {
+        CursorConfig {
+            blink_interval: Duration::from_millis(500),
+            hollow_when_unfocused: true,
+        }
+    }
+}
+
+struct CursorAppearance {
+    shape: CursorShape,
+    color: Color32,
+    hollow: bool,
+}
+
 fn paint_cursor(
     label_rect: Rect,
     character_size: &(f32, f32),
     cursor_pos: &CursorState,
     terminal_buf: &[u8],
+    appearance: &CursorAppearance,
     ui: &mut Ui,
 ) {
     let painter = ui.painter();
@@ -64,14 +236,27 @@ fn paint_cursor(
     let bottom = label_rect.bottom();
     let left = label_rect.left();
     let cursor_offset = character_to_cursor_offset(cursor_pos, character_size, terminal_buf);
-    painter.rect_filled(
-        Rect::from_min_size(
-            egui::pos2(left + cursor_offset.0, bottom + cursor_offset.1),
-            egui::vec2(character_size.0, character_size.1),
-        ),
-        0.0,
-        Color32::GRAY,
+    let cell = Rect::from_min_size(
+        egui::pos2(left + cursor_offset.0, bottom + cursor_offset.1),
+        egui::vec2(character_size.0, character_size.1),
     );
+
+    let thickness = (character_size.1 / 10.0).max(1.0);
+    let rect = match appearance.shape {
+        CursorShape::Block => cell,
+        CursorShape::Underline => {
+            Rect::from_min_max(egui::pos2(cell.left(), cell.bottom() - thickness), cell.max)
+        }
+        CursorShape::Bar => {
+            Rect::from_min_max(cell.min, egui::pos2(cell.left() + thickness, cell.bottom()))
+        }
+    };
+
+    if appearance.hollow && appearance.shape == CursorShape::Block {
+        painter.rect_stroke(rect, 0.0, egui::Stroke::new(1.0, appearance.color));
+    } else {
+        painter.rect_filled(rect, 0.0, appearance.color);
+    }
 }
 
 fn setup_fonts(ctx: &egui::Context) {
@@ -105,13 +290,88 @@ fn setup_fonts(ctx: &egui::Context) {
     ctx.set_fonts(fonts);
 }
 
+/// Multi-line pastes run every line immediately unless the application enabled bracketed paste,
+/// so optionally ask first
+struct PasteConfirmation {
+    enabled: bool,
+    pending: Option<String>,
+}
+
+impl PasteConfirmation {
+    fn needs_confirmation(&self, text: &str, terminal_emulator: &TerminalEmulator) -> bool {
+        self.enabled && !terminal_emulator.core().bracketed_paste() && text.contains('\n')
+    }
+
+    /// Shows the dialog while a paste is pending, returning the text once the user accepts it
+    fn show(&mut self, ctx: &egui::Context) -> Option<String> {
+        let text = self.pending.as_ref()?;
+
+        let mut pasted = false;
+        let mut cancelled = false;
+        egui::Window::new("Paste multiple lines?")
+            .collapsible(false)
+            .resizable(false)
+            .anchor(egui::Align2::CENTER_CENTER, egui::vec2(0.0, 0.0))
+            .show(ctx, |ui| {
+                ui.label(format!(
+                    "The pasted text contains {} lines, which may run as separate commands.",
+                    text.lines().count()
+                ));
+                ui.horizontal(|ui| {
+                    pasted = ui.button("Paste").clicked();
+                    cancelled = ui.button("Cancel").clicked();
+                });
+            });
+
+        if cancelled {
+            self.pending = None;
+        }
+
+        if pasted {
+            self.pending.take()
+        } else {
+            None
+        }
+    }
+}
+
+/// Everything a frame draws, copied out of the emulator so that drawing doesn't hold its lock
+struct FrameState {
+    snapshot: TerminalSnapshot,
+    cursor_style: CursorStyle,
+    cursor_visible: bool,
+    synchronized_update_remaining: Option<Duration>,
+}
+
+impl FrameState {
+    fn new(terminal_emulator: &TerminalEmulator) -> FrameState {
+        let core = terminal_emulator.core();
+        FrameState {
+            snapshot: core.snapshot(),
+            cursor_style: core.cursor_style(),
+            cursor_visible: core.cursor_visible(),
+            synchronized_update_remaining: core.synchronized_update_remaining(),
+        }
+    }
+}
+
 struct TerminalGui {
-    terminal_emulator: TerminalEmulator,
+    terminal_emulator: Arc<Mutex<TerminalEmulator>>,
+    events: Receiver<TerminalEvent>,
     character_size: Option<(f32, f32)>,
+    terminal_origin: Option<Pos2>,
+    paste_confirmation: PasteConfirmation,
+    focused: bool,
+    cursor_config: CursorConfig,
+    hold: bool,
 }
 
 impl TerminalGui {
-    fn new(cc: &eframe::CreationContext<'_>, terminal_emulator: TerminalEmulator) -> Self {
+    fn new(
+        cc: &eframe::CreationContext<'_>,
+        terminal_emulator: TerminalEmulator,
+        window_config: WindowConfig,
+    ) -> Result<Self, TerminalError> {
         cc.egui_ctx.style_mut(|style| {
             style.override_text_style = Some(TextStyle::Monospace);
         });
@@ -119,10 +379,23 @@ impl TerminalGui {
         cc.egui_ctx.set_pixels_per_point(1.5);
         setup_fonts(&cc.egui_ctx);
 
-        TerminalGui {
+        let terminal_emulator = Arc::new(Mutex::new(terminal_emulator));
+        let ctx = cc.egui_ctx.clone();
+        let events = spawn_reader_thread(terminal_emulator.clone(), move || ctx.request_repaint())?;
+
+        Ok(TerminalGui {
             terminal_emulator,
+            events,
             character_size: None,
-        }
+            terminal_origin: None,
+            paste_confirmation: PasteConfirmation {
+                enabled: window_config.confirm_paste,
+                pending: None,
+            },
+            focused: true,
+            cursor_config: window_config.cursor,
+            hold: window_config.hold,
+        })
     }
 }
 
@@ -132,34 +405,91 @@ impl eframe::App for TerminalGui {
             self.character_size = Some(get_char_size(ctx));
         }
 
-        self.terminal_emulator.read();
+        // The lock is only held to apply input and copy out what to draw, the reader thread
+        // needs it to keep up with the child while the frame is laid out
+        let mut terminal_emulator = self.terminal_emulator.lock().unwrap();
 
-        CentralPanel::default().show(ctx, |ui| {
-            ui.input(|input_state| {
-                write_input_to_terminal(input_state, &mut self.terminal_emulator);
-            });
+        while let Ok(TerminalEvent::ChildExited { status }) = self.events.try_recv() {
+            log::info!(target: "gui", "Child {status}");
+            if self.hold {
+                terminal_emulator
+                    .core_mut()
+                    .show_message(&format!("[process {status}]"));
+            } else {
+                ctx.send_viewport_cmd(egui::ViewportCommand::Close);
+            }
+        }
+
+        let focused = ctx.input(|input| input.viewport().focused).unwrap_or(true);
+        if focused != self.focused {
+            self.focused = focused;
+            if let Err(e) = terminal_emulator.focus_changed(focused) {
+                log::warn!(target: "gui", "{e}");
+            }
+        }
+
+        let character_size = self.character_size.unwrap();
+        let palette = terminal_emulator.core().palette().clone();
+        drop(terminal_emulator);
+
+        let mut synchronized_update_remaining = None;
+        let panel_frame =
+            Frame::central_panel(&ctx.style()).fill(rgb_to_color32(palette.background));
+
+        CentralPanel::default().frame(panel_frame).show(ctx, |ui| {
+            let mut terminal_emulator = self.terminal_emulator.lock().unwrap();
+            let available = ui.available_size();
+            terminal_emulator.set_win_size(
+                (available.x / character_size.0) as usize,
+                (available.y / character_size.1) as usize,
+            );
+
+            // Keep input away from the terminal while the paste dialog has the user's attention
+            if self.paste_confirmation.pending.is_none() {
+                ui.input(|input_state| {
+                    write_input_to_terminal(
+                        input_state,
+                        &mut terminal_emulator,
+                        self.terminal_origin,
+                        &character_size,
+                        &mut self.paste_confirmation,
+                    );
+                });
+            }
+
+            let frame = FrameState::new(&terminal_emulator);
+            drop(terminal_emulator);
+            synchronized_update_remaining = frame.synchronized_update_remaining;
+
+            // Apps in the middle of a synchronized update have only drawn part of a frame, the
+            // snapshot keeps showing the last full one
+            let TerminalSnapshot {
+                data,
+                format_data,
+                cursor_pos,
+            } = &frame.snapshot;
 
             let response = unsafe {
                 let style = &ctx.style().text_styles[&TextStyle::Monospace];
                 let mut job = egui::text::LayoutJob::simple(
-                    std::str::from_utf8_unchecked(self.terminal_emulator.data()).to_string(),
+                    std::str::from_utf8_unchecked(data).to_string(),
                     style.clone(),
-                    ctx.style().visuals.text_color(),
-                    ui.available_width(),
+                    rgb_to_color32(palette.foreground),
+                    // Lines are already laid out by the terminal
+                    f32::INFINITY,
                 );
 
                 let mut textformat = job.sections[0].format.clone();
                 job.sections.clear();
-                let default_color = textformat.color;
                 let bold_font_family = FontFamily::Name(BOLD_FONT_NAME.to_string().into());
                 let regular_font_family = FontFamily::Name(REGULAR_FONT_NAME.to_string().into());
 
-                for tag in self.terminal_emulator.format_data() {
+                for tag in format_data {
                     let mut range = tag.start..tag.end;
                     let color = tag.color;
                 
                     if range.end == usize::MAX {
-                        range.end = self.terminal_emulator.data().len()
+                        range.end = data.len()
                     }
 
                     if tag.bold {
@@ -169,17 +499,7 @@ impl eframe::App for TerminalGui {
                         textformat.font_id.family = regular_font_family.clone();
                     }
 
-                    textformat.color = match color {
-                        TerminalColor::Default => default_color,
-                        TerminalColor::Black => Color32::BLACK,
-                        TerminalColor::Red => Color32::RED,
-                        TerminalColor::Green => Color32::GREEN,
-                        TerminalColor::Yellow => Color32::YELLOW,
-                        TerminalColor::Blue => Color32::BLUE,
-                        TerminalColor::Magenta => Color32::from_rgb(255, 0, 255),
-                        TerminalColor::Cyan => Color32::from_rgb(0, 255, 255),
-                        TerminalColor::White => Color32::WHITE,
-                    };
+                    textformat.color = rgb_to_color32(palette.resolve(color));
 
                     job.sections.push(egui::text::LayoutSection {
                         leading_space: 0.0f32,
@@ -192,23 +512,117 @@ impl eframe::App for TerminalGui {
                 ui.label(job)
             };
 
-            paint_cursor(
-                response.rect,
-                self.character_size.as_ref().unwrap(),
-                &self.terminal_emulator.cursor_pos(),
-                self.terminal_emulator.data(),
-                ui,
-            );
+            self.terminal_origin = Some(response.rect.min);
+
+            let cursor_style = frame.cursor_style;
+            let blink_interval = self.cursor_config.blink_interval.as_secs_f64();
+            let blink_visible = if cursor_style.blinking && self.focused && blink_interval > 0.0 {
+                let time = ui.input(|input| input.time);
+                ctx.request_repaint_after_secs((blink_interval - time % blink_interval) as f32);
+                ((time / blink_interval) as u64).is_multiple_of(2)
+            } else {
+                true
+            };
+
+            if frame.cursor_visible && blink_visible {
+                let appearance = CursorAppearance {
+                    shape: cursor_style.shape,
+                    color: rgb_to_color32(palette.cursor),
+                    hollow: !self.focused && self.cursor_config.hollow_when_unfocused,
+                };
+                paint_cursor(
+                    response.rect,
+                    &character_size,
+                    cursor_pos,
+                    data,
+                    &appearance,
+                    ui,
+                );
+            }
         });
+
+        if let Some(text) = self.paste_confirmation.show(ctx) {
+            if let Err(e) = self.terminal_emulator.lock().unwrap().paste(&text) {
+                log::warn!(target: "gui", "{e}");
+            }
+        }
+
+        if let Some(remaining) = synchronized_update_remaining {
+            ctx.request_repaint_after(remaining);
+        }
+    }
+}
+
+/// Window settings that come from the command line or config file
+pub struct WindowConfig {
+    pub title: String,
+    /// X11 WM_CLASS or Wayland app id, used by window managers and desktop files to match windows
+    pub class: Option<String>,
+    /// Keep the window open after the child exits
+    pub hold: bool,
+    /// Ask before pasting multiple lines when the application hasn't enabled bracketed paste
+    pub confirm_paste: bool,
+    pub cursor: CursorConfig,
+}
+
+impl Default for WindowConfig {
+    fn default() -> WindowConfig {
+        WindowConfig {
+            title: "cmlterm".to_string(),
+            class: None,
+            hold: false,
+            confirm_paste: true,
+            cursor: CursorConfig::default(),
+        }
     }
 }
 
-pub fn run(terminal_emulator: TerminalEmulator) {
-    let native_options = eframe::NativeOptions::default();
+pub fn run(
+    terminal_emulator: TerminalEmulator,
+    window_config: WindowConfig,
+) -> Result<(), eframe::Error> {
+    let mut viewport = egui::ViewportBuilder::default().with_title(&window_config.title);
+    if let Some(class) = &window_config.class {
+        viewport = viewport.with_app_id(class);
+    }
+
+    let native_options = eframe::NativeOptions {
+        viewport,
+        // The reader thread asks for a repaint after every batch of output, vsync keeps a flood
+        // of those down to one frame per refresh showing the latest state
+        vsync: true,
+        ..Default::default()
+    };
     eframe::run_native(
         "cmlterm",
         native_options,
-        Box::new(move |cc| Ok(Box::new(TerminalGui::new(cc, terminal_emulator)))),
+        Box::new(move |cc| {
+            Ok(Box::new(TerminalGui::new(
+                cc,
+                terminal_emulator,
+                window_config,
+            )?))
+        }),
     )
-    .unwrap();
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_pointer_to_cell() {
+        let origin = Pos2::new(10.0, 20.0);
+        let size = (8.0, 16.0);
+        let cell = |x, y| pointer_to_cell(Pos2::new(x, y), origin, &size, (80, 24));
+
+        assert_eq!(cell(10.0, 20.0), (0, 0));
+        assert_eq!(cell(27.0, 53.0), (2, 2));
+        // Past the right and bottom edges
+        assert_eq!(cell(10.0 + 8.0 * 80.0, 30.0), (79, 0));
+        assert_eq!(cell(30.0, 5000.0), (2, 23));
+        assert_eq!(cell(5000.0, 5000.0), (79, 23));
+        // In the padding above and left of the grid
+        assert_eq!(cell(0.0, 0.0), (0, 0));
+    }
 }
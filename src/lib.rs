@@ -0,0 +1,4 @@
+//! The terminal emulator without a window, a library so that the fuzz targets in fuzz/ can link
+//! against it
+
+pub mod terminal_emulator;
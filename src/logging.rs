@@ -0,0 +1,163 @@
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::Write,
+    path::PathBuf,
+    sync::{Mutex, PoisonError},
+};
+
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Read for the filter if neither the command line nor the config file give one
+const FILTER_ENV: &str = "CMLTERM_LOG";
+const DEFAULT_FILTER: &str = "warn";
+
+/// Where diagnostics go and how much of them. Each subsystem logs to its own target, parser,
+/// emulator, pty, gui or config, so e.g. "warn,parser=debug" shows everything the parser doesn't handle
+#[derive(Clone, Debug, Default)]
+pub struct LogConfig {
+    /// Comma separated list of either a level for all targets or target=level
+    pub filter: Option<String>,
+    /// Log to this file instead of stderr
+    pub file: Option<PathBuf>,
+}
+
+#[derive(Debug, Eq, PartialEq)]
+struct Filter {
+    default: LevelFilter,
+    targets: HashMap<String, LevelFilter>,
+}
+
+impl Filter {
+    fn parse(spec: &str) -> Result<Filter, String> {
+        let mut filter = Filter {
+            default: LevelFilter::Warn,
+            targets: HashMap::new(),
+        };
+
+        for directive in spec.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            let parse_level = |level: &str| {
+                level
+                    .parse::<LevelFilter>()
+                    .map_err(|_| format!("invalid log level {level:?}"))
+            };
+
+            match directive.split_once('=') {
+                Some((target, level)) => {
+                    filter
+                        .targets
+                        .insert(target.to_string(), parse_level(level)?);
+                }
+                None => filter.default = parse_level(directive)?,
+            }
+        }
+
+        Ok(filter)
+    }
+
+    /// Level for a target, which also applies to anything below it, so cmlterm covers
+    /// cmlterm::config
+    fn level(&self, target: &str) -> LevelFilter {
+        let mut target = target;
+        loop {
+            if let Some(level) = self.targets.get(target) {
+                return *level;
+            }
+
+            match target.rsplit_once("::") {
+                Some((parent, _)) => target = parent,
+                None => return self.default,
+            }
+        }
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.targets.values().copied().fold(self.default, Ord::max)
+    }
+}
+
+struct Logger {
+    filter: Filter,
+    output: Mutex<Box<dyn Write + Send>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.filter.level(metadata.target())
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let mut output = self.output.lock().unwrap_or_else(PoisonError::into_inner);
+        // Nowhere left to report a failure to log
+        let _ = writeln!(
+            output,
+            "[{} {}] {}",
+            record.level(),
+            record.target(),
+            record.args()
+        );
+    }
+
+    fn flush(&self) {
+        let _ = self
+            .output
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .flush();
+    }
+}
+
+/// Installs the logger, should be called once before anything else logs
+pub fn init(config: &LogConfig) -> Result<(), String> {
+    let spec = config
+        .filter
+        .clone()
+        .or_else(|| std::env::var(FILTER_ENV).ok())
+        .unwrap_or_else(|| DEFAULT_FILTER.to_string());
+    let filter = Filter::parse(&spec)?;
+
+    let output: Box<dyn Write + Send> = match &config.file {
+        Some(path) => Box::new(
+            File::options()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("failed to open {}: {e}", path.display()))?,
+        ),
+        None => Box::new(std::io::stderr()),
+    };
+
+    log::set_max_level(filter.max_level());
+    let logger = Box::leak(Box::new(Logger {
+        filter,
+        output: Mutex::new(output),
+    }));
+    log::set_logger(logger).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filter() {
+        let filter = Filter::parse("info, parser=debug,cmlterm=error,gui=off").unwrap();
+        assert_eq!(filter.level("parser"), LevelFilter::Debug);
+        assert_eq!(filter.level("gui"), LevelFilter::Off);
+        assert_eq!(filter.level("pty"), LevelFilter::Info);
+        assert_eq!(filter.level("cmlterm::config"), LevelFilter::Error);
+        assert_eq!(filter.level("egui_glow::painter"), LevelFilter::Info);
+        assert_eq!(filter.max_level(), LevelFilter::Debug);
+
+        assert_eq!(
+            Filter::parse("").unwrap().level("parser"),
+            LevelFilter::Warn
+        );
+        assert!(Filter::parse("parser=loud").is_err());
+        assert!(Filter::parse("verbose").is_err());
+    }
+}
@@ -1,9 +1,48 @@
-use terminal_emulator::TerminalEmulator;
+use cli::Action;
+use cmlterm::terminal_emulator::{self, TerminalEmulator};
 
+mod cli;
+mod config;
 mod gui;
-mod terminal_emulator;
+mod logging;
 
 fn main() {
-  let terminal_emulator = TerminalEmulator::new();
-  gui::run(terminal_emulator);
+  let options = match cli::parse_args(std::env::args().skip(1)) {
+    Ok(Action::Run(options)) => options,
+    Ok(Action::Help) => {
+      println!("{}", cli::USAGE);
+      return;
+    }
+    Ok(Action::Version) => {
+      println!("cmlterm {}", env!("CARGO_PKG_VERSION"));
+      return;
+    }
+    Err(e) => {
+      eprintln!("cmlterm: {e}\n\n{}", cli::USAGE);
+      std::process::exit(2);
+    }
+  };
+
+  if let Err(e) = logging::init(&options.log) {
+    eprintln!("cmlterm: {e}");
+    std::process::exit(2);
+  }
+  for warning in &options.warnings {
+    log::warn!(target: "config", "{warning}");
+  }
+
+  let terminal_emulator = match TerminalEmulator::new(&options.session) {
+    Ok(terminal_emulator) => terminal_emulator,
+    Err(e) => {
+      eprintln!("cmlterm: {e}");
+      std::process::exit(1);
+    }
+  };
+
+  let result = gui::run(terminal_emulator, options.window);
+  terminal_emulator::log_unhandled_summary();
+  if let Err(e) = result {
+    eprintln!("cmlterm: {e}");
+    std::process::exit(1);
+  }
 }
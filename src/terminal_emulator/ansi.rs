@@ -1,3 +1,7 @@
+use super::charset::Charset;
+use super::palette::{parse_color_spec, DynamicColor, Rgb};
+use super::unhandled;
+
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub enum SelectGraphicRendition {
     // NOTE: Non-exhaustive list
@@ -46,271 +50,1052 @@ impl SelectGraphicRendition {
             _ => Self::Unknown(val),
         }
     }
+
+    /// The parameter that selects this, the inverse of from_usize
+    pub fn code(self) -> usize {
+        match self {
+            SelectGraphicRendition::Reset => 0,
+            SelectGraphicRendition::Bold => 1,
+            SelectGraphicRendition::Black => 30,
+            SelectGraphicRendition::Red => 31,
+            SelectGraphicRendition::Green => 32,
+            SelectGraphicRendition::Yellow => 33,
+            SelectGraphicRendition::Blue => 34,
+            SelectGraphicRendition::Magenta => 35,
+            SelectGraphicRendition::Cyan => 36,
+            SelectGraphicRendition::White => 37,
+            SelectGraphicRendition::BrightBlack => 90,
+            SelectGraphicRendition::BrightRed => 91,
+            SelectGraphicRendition::BrightGreen => 92,
+            SelectGraphicRendition::BrightYellow => 93,
+            SelectGraphicRendition::BrightBlue => 94,
+            SelectGraphicRendition::BrightMagenta => 95,
+            SelectGraphicRendition::BrightCyan => 96,
+            SelectGraphicRendition::BrightWhite => 97,
+            SelectGraphicRendition::Unknown(val) => val,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum CursorShape {
+    Block,
+    Underline,
+    Bar,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct CursorStyle {
+    pub shape: CursorShape,
+    pub blinking: bool,
+}
+
+impl CursorStyle {
+    /// Parameter of DECSCUSR (CSI Ps SP q)
+    fn from_decscusr(val: usize) -> Option<CursorStyle> {
+        let (shape, blinking) = match val {
+            0 | 1 => (CursorShape::Block, true),
+            2 => (CursorShape::Block, false),
+            3 => (CursorShape::Underline, true),
+            4 => (CursorShape::Underline, false),
+            5 => (CursorShape::Bar, true),
+            6 => (CursorShape::Bar, false),
+            _ => return None,
+        };
+
+        Some(CursorStyle { shape, blinking })
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DecMode {
+    // NOTE: Non-exhaustive list
+    /// DECOM, cursor positions are relative to the scroll region
+    Origin,
+    /// DECAWM, printing past the last column continues on the next line
+    AutoWrap,
+    ShowCursor,
+    X10Mouse,
+    NormalMouse,
+    ButtonEventMouse,
+    AnyEventMouse,
+    Utf8Mouse,
+    SgrMouse,
+    UrxvtMouse,
+    FocusReporting,
+    BracketedPaste,
+    SynchronizedUpdate,
+    Unknown(usize),
+}
+
+impl DecMode {
+    fn from_usize(val: usize) -> DecMode {
+        match val {
+            6 => DecMode::Origin,
+            7 => DecMode::AutoWrap,
+            9 => DecMode::X10Mouse,
+            25 => DecMode::ShowCursor,
+            1000 => DecMode::NormalMouse,
+            1002 => DecMode::ButtonEventMouse,
+            1003 => DecMode::AnyEventMouse,
+            1004 => DecMode::FocusReporting,
+            1005 => DecMode::Utf8Mouse,
+            1006 => DecMode::SgrMouse,
+            1015 => DecMode::UrxvtMouse,
+            2004 => DecMode::BracketedPaste,
+            2026 => DecMode::SynchronizedUpdate,
+            _ => DecMode::Unknown(val),
+        }
+    }
+
+    pub fn to_usize(self) -> usize {
+        match self {
+            DecMode::Origin => 6,
+            DecMode::AutoWrap => 7,
+            DecMode::X10Mouse => 9,
+            DecMode::ShowCursor => 25,
+            DecMode::NormalMouse => 1000,
+            DecMode::ButtonEventMouse => 1002,
+            DecMode::AnyEventMouse => 1003,
+            DecMode::FocusReporting => 1004,
+            DecMode::Utf8Mouse => 1005,
+            DecMode::SgrMouse => 1006,
+            DecMode::UrxvtMouse => 1015,
+            DecMode::BracketedPaste => 2004,
+            DecMode::SynchronizedUpdate => 2026,
+            DecMode::Unknown(val) => val,
+        }
+    }
 }
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum TerminalOutput {
     SetCursorPos { x: Option<usize>, y: Option<usize> },
+    /// CUU, CUD, CUF and CUB, which stop at the margins
+    CursorUp(usize),
+    CursorDown(usize),
+    CursorForward(usize),
+    CursorBackward(usize),
+    /// CNL and CPL, like CUD and CUU but also back to the first column
+    CursorNextLine(usize),
+    CursorPrevLine(usize),
     ClearForwards,
     ClearBackwards,
     ClearAll,
+    ClearLineForwards,
+    ClearLineBackwards,
+    ClearLine,
+    /// ECH, blanks characters from the cursor on without moving the rest of the line
+    EraseCharacters(usize),
+    /// ICH, inserts blanks at the cursor, pushing the rest of the line right
+    InsertCharacters(usize),
+    /// DCH, deletes characters at the cursor, pulling the rest of the line left
+    DeleteCharacters(usize),
+    /// IL, inserts blank lines at the cursor, pushing the lines below towards the bottom margin
+    InsertLines(usize),
+    /// DL, deletes lines at the cursor, pulling up the lines below from the bottom margin
+    DeleteLines(usize),
+    /// SU, scrolls the scroll region up
+    ScrollUp(usize),
+    /// SD, scrolls the scroll region down
+    ScrollDown(usize),
+    /// DECSTBM, 1 based rows. No bottom means the bottom of the screen
+    SetScrollRegion { top: usize, bottom: Option<usize> },
+    /// IND, moves down a line, scrolling at the bottom margin
+    Index,
+    /// NEL, like IND but also back to the first column
+    NextLine,
+    /// RI, moves up a line, scrolling at the top margin
+    ReverseIndex,
+    /// DECSC, remembers the cursor position, rendition, origin mode and charsets
+    SaveCursor,
+    /// DECRC
+    RestoreCursor,
     Sgr(SelectGraphicRendition),
+    /// Only produced by push, the emulator gets its data through Perform::print
     Data(Vec<u8>),
+    SetPaletteColor { index: usize, color: Rgb },
+    QueryPaletteColor(usize),
+    ResetPaletteColor(Option<usize>),
+    SetDynamicColor { target: DynamicColor, color: Rgb },
+    QueryDynamicColor(DynamicColor),
+    ResetDynamicColor(DynamicColor),
+    RequestStatusReport,
+    RequestCursorPosition { extended: bool },
+    RequestDeviceAttributes,
+    RequestSecondaryDeviceAttributes,
+    RequestTerminalVersion,
+    SetMode(DecMode),
+    ResetMode(DecMode),
+    RequestMode(DecMode),
+    SetCursorStyle(CursorStyle),
+    DesignateCharset { slot: usize, charset: Charset },
+    /// Invokes the given G0-G3 charset until the next locking shift
+    LockingShift(usize),
+    /// Invokes the given G0-G3 charset for the next character only
+    SingleShift(usize),
+    SetTabStop,
+    ClearTabStop,
+    ClearAllTabStops,
+    ForwardTab(usize),
+    BackwardTab(usize),
+    /// REP, writes the last printed character again
+    RepeatCharacter(usize),
+    /// SL, shifts every line left by the given number of columns
+    ScrollLeft(usize),
+    /// SR, shifts every line right by the given number of columns
+    ScrollRight(usize),
+    /// DECIC, inserts blank columns at the cursor
+    InsertColumns(usize),
+    /// DECDC, deletes columns at the cursor
+    DeleteColumns(usize),
+    /// RIS, puts everything back to the power on state
+    FullReset,
+    /// DECSTR, resets modes and rendition but leaves the screen alone
+    SoftReset,
+    /// DECALN, fills the screen with E
+    ScreenAlignmentTest,
     Invalid,
 }
 
-enum CsiParserState {
-    Params,
-    Intermediates,
-    Finished(u8),
-    Invalid,
-    InvalidFinished,
-}
+/// Parameters past this many make a sequence invalid, xterm allows 30
+const MAX_PARAMS: usize = 32;
+/// Sub-parameters past this many make a sequence invalid, the longest in use is a colon separated
+/// direct color
+const MAX_SUBPARAMS: usize = 8;
+/// Parameter values are clamped to this rather than overflowing
+const MAX_PARAM_VALUE: usize = u16::MAX as usize;
 
-fn is_csi_terminator(b: u8) -> bool {
-    (0x40..=0x7d).contains(&b)
+/// A parameter and the colon separated sub-parameters that follow it, e.g. 4:3 is a value of 4
+/// with a sub-parameter of 3. Empty values are None, so each user can pick its own default
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct Param {
+    pub value: Option<usize>,
+    pub subparams: Vec<Option<usize>>,
 }
 
-fn is_csi_param(b: u8) -> bool {
-    (0x30..=0x3f).contains(&b)
+/// A CSI sequence, also used for the header of a DCS string which has the same form
+#[derive(Debug, Default, Eq, PartialEq)]
+pub struct Csi {
+    /// One of ?, >, < or = at the start of the parameters, which marks a private sequence
+    pub private_marker: Option<u8>,
+    pub params: Vec<Param>,
+    pub intermediates: Vec<u8>,
+    pub final_byte: u8,
 }
 
-fn is_csi_intermediate(b: u8) -> bool {
-    (0x20..=0x2f).contains(&b)
+impl Csi {
+    /// Value of the parameter at idx, None if it was left out
+    pub fn param(&self, idx: usize) -> Option<usize> {
+        self.params.get(idx).and_then(|param| param.value)
+    }
+
+    fn clear(&mut self) {
+        self.private_marker = None;
+        self.params.clear();
+        self.intermediates.clear();
+        self.final_byte = 0;
+    }
+
+    /// Adds a parameter byte, returning false if the sequence has more parameters than we keep
+    fn push_param_byte(&mut self, b: u8) -> bool {
+        if self.params.is_empty() {
+            self.params.push(Param::default());
+        }
+
+        let param = self.params.last_mut().expect("pushed above");
+        match b {
+            b'0'..=b'9' => {
+                let value = match param.subparams.last_mut() {
+                    Some(subparam) => subparam,
+                    None => &mut param.value,
+                };
+                let digit = (b - b'0') as usize;
+                *value = Some((value.unwrap_or(0) * 10 + digit).min(MAX_PARAM_VALUE));
+            }
+            b':' if param.subparams.len() < MAX_SUBPARAMS => param.subparams.push(None),
+            b';' if self.params.len() < MAX_PARAMS => self.params.push(Param::default()),
+            _ => return false,
+        }
+
+        true
+    }
 }
 
-fn extract_param(idx: usize, params: &[Option<usize>]) -> Option<usize> {
-    params.get(idx).copied().flatten()
+fn parse_sgr<F: FnMut(TerminalOutput)>(params: &[Param], emit: &mut F) {
+    let mut params = params.iter();
+    while let Some(param) = params.next() {
+        let Some(value) = param.value else {
+            continue;
+        };
+
+        let sgr = match value {
+            // Extended colors, 5;n is an index into the 256 color palette and 2;r;g;b a direct
+            // color. The arguments are either the following parameters or colon separated
+            // sub-parameters, and have to be skipped either way so they aren't read as attributes
+            38 | 48 | 58 => {
+                let (kind, index) = match param.subparams.first() {
+                    Some(&kind) => (kind, param.subparams.get(1).copied().flatten()),
+                    None => {
+                        let kind = params.next().and_then(|param| param.value);
+                        let arg_count = match kind {
+                            Some(5) => 1,
+                            Some(2) => 3,
+                            _ => 0,
+                        };
+                        let mut args = params.by_ref().take(arg_count);
+                        let index = args.next().and_then(|param| param.value);
+                        args.for_each(drop);
+                        (kind, index)
+                    }
+                };
+
+                // Only the first 16 palette entries are colors we have
+                match (value, kind, index) {
+                    (38, Some(5), Some(index @ 0..=7)) => {
+                        SelectGraphicRendition::from_usize(30 + index)
+                    }
+                    (38, Some(5), Some(index @ 8..=15)) => {
+                        SelectGraphicRendition::from_usize(90 + index - 8)
+                    }
+                    _ => SelectGraphicRendition::Unknown(value),
+                }
+            }
+            // Sub-parameters only refine attributes we don't have, so they are left out
+            _ => SelectGraphicRendition::from_usize(value),
+        };
+        emit(TerminalOutput::Sgr(sgr));
+    }
 }
 
-fn split_params_into_semicolon_delimited_usize(params: &[u8]) -> Result<Vec<Option<usize>>, ()> {
-    let params = params
-        .split(|b| *b == b';')
-        .map(parse_param_as_usize)
-        .collect::<Result<Vec<Option<usize>>, ()>>();
+/// OSC arguments are arbitrary bytes, unlike CSI params which are filtered by the parser
+fn parse_osc_number(arg: &[u8]) -> Option<usize> {
+    std::str::from_utf8(arg).ok()?.parse().ok()
+}
 
-    params
+fn parse_palette_index(index: &[u8]) -> Option<usize> {
+    let index = parse_osc_number(index)?;
+    if index > 255 {
+        return None;
+    }
+    Some(index)
 }
 
-fn parse_param_as_usize(param_bytes: &[u8]) -> Result<Option<usize>, ()> {
-    let param_str =
-        std::str::from_utf8(param_bytes).expect("parameter should always be valid utf8");
-    if param_str.is_empty() {
-        return Ok(None);
+/// OSC 4 takes a list of index/spec pairs, each of which is either set or queried
+fn parse_osc_palette<F: FnMut(TerminalOutput)>(mut args: &[&[u8]], emit: &mut F) {
+    if args.is_empty() || !args.len().is_multiple_of(2) {
+        log::debug!(target: "parser", "Invalid palette osc");
+        emit(TerminalOutput::Invalid);
+        return;
+    }
+
+    while let [index, spec, rest @ ..] = args {
+        args = rest;
+
+        let Some(index) = parse_palette_index(index) else {
+            log::debug!(target: "parser", "Invalid palette index");
+            emit(TerminalOutput::Invalid);
+            continue;
+        };
+
+        if *spec == b"?" {
+            emit(TerminalOutput::QueryPaletteColor(index));
+        } else if let Some(color) = parse_color_spec(spec) {
+            emit(TerminalOutput::SetPaletteColor { index, color });
+        } else {
+            log::debug!(target: "parser", "Invalid color spec {:?}", String::from_utf8_lossy(spec));
+            emit(TerminalOutput::Invalid);
+        }
     }
-    let param = param_str.parse().map_err(|_| ())?;
-    Ok(Some(param))
 }
 
-struct CsiParser {
-    state: CsiParserState,
-    params: Vec<u8>,
-    intermediates: Vec<u8>,
+/// OSC 10-12 set their own color followed by the next dynamic colors in order, so OSC 10 with
+/// three arguments sets foreground, background and cursor
+fn parse_osc_dynamic_colors<F: FnMut(TerminalOutput)>(code: usize, args: &[&[u8]], emit: &mut F) {
+    for (i, spec) in args.iter().enumerate() {
+        let Some(target) = DynamicColor::from_osc(code + i) else {
+            break;
+        };
+
+        if *spec == b"?" {
+            emit(TerminalOutput::QueryDynamicColor(target));
+        } else if let Some(color) = parse_color_spec(spec) {
+            emit(TerminalOutput::SetDynamicColor { target, color });
+        } else {
+            log::debug!(target: "parser", "Invalid color spec {:?}", String::from_utf8_lossy(spec));
+            emit(TerminalOutput::Invalid);
+        }
+    }
 }
 
-impl CsiParser {
-    fn new() -> CsiParser {
-        CsiParser {
-            state: CsiParserState::Params,
-            params: Vec::new(),
-            intermediates: Vec::new(),
+/// Turns the contents of an OSC string into the outputs it stands for
+pub fn dispatch_osc<F: FnMut(TerminalOutput)>(data: &[u8], emit: &mut F) {
+    let mut args = data.split(|b| *b == b';');
+    let code = args.next().and_then(parse_osc_number);
+    let args = args.collect::<Vec<_>>();
+
+    let Some(code) = code else {
+        log::debug!(target: "parser", "Invalid osc sequence");
+        emit(TerminalOutput::Invalid);
+        return;
+    };
+
+    match code {
+        4 => parse_osc_palette(&args, emit),
+        10..=12 => parse_osc_dynamic_colors(code, &args, emit),
+        104 => {
+            let indices = args
+                .iter()
+                .filter(|arg| !arg.is_empty())
+                .collect::<Vec<_>>();
+            if indices.is_empty() {
+                emit(TerminalOutput::ResetPaletteColor(None));
+            }
+
+            for index in indices {
+                let Some(index) = parse_palette_index(index) else {
+                    log::debug!(target: "parser", "Invalid palette index");
+                    emit(TerminalOutput::Invalid);
+                    continue;
+                };
+                emit(TerminalOutput::ResetPaletteColor(Some(index)));
+            }
         }
+        110..=112 => {
+            let target = DynamicColor::from_osc(code - 100).expect("code is in range");
+            emit(TerminalOutput::ResetDynamicColor(target));
+        }
+        _ => {
+            unhandled::record("parser", "OSC", code);
+            emit(TerminalOutput::Invalid);
+        }
+    }
+}
+
+/// Counts a sequence we don't support by its marker, intermediates and final byte, e.g. "CSI ?$p"
+fn record_unhandled_csi(csi: &Csi) {
+    let marker = csi.private_marker.into_iter();
+    let intermediates = csi.intermediates.iter().copied();
+    let bytes = marker.chain(intermediates).chain([csi.final_byte]);
+    unhandled::record_bytes("parser", "CSI", bytes);
+}
+
+/// Turns a finished CSI sequence into the outputs it stands for
+pub fn dispatch_csi<F: FnMut(TerminalOutput)>(csi: &Csi, ignore: bool, emit: &mut F) {
+    if ignore {
+        log::debug!(target: "parser", "Invalid csi sequence");
+        emit(TerminalOutput::Invalid);
+        return;
     }
 
-    fn push(&mut self, b: u8) {
-        if let CsiParserState::Finished(_) | CsiParserState::InvalidFinished = &self.state {
-            panic!("CsiParser should not be pushed to once finished");
+    let marker = csi.private_marker;
+    match (marker, &csi.intermediates[..], csi.final_byte) {
+        (None, b"", b'H' | b'f') => {
+            // Row first, then column
+            emit(TerminalOutput::SetCursorPos {
+                x: Some(csi.param(1).unwrap_or(1)),
+                y: Some(csi.param(0).unwrap_or(1)),
+            });
         }
+        (None, b"", b'd') => {
+            emit(TerminalOutput::SetCursorPos {
+                x: None,
+                y: Some(csi.param(0).unwrap_or(1)),
+            });
+        }
+        (
+            None,
+            b"",
+            b'A' | b'B' | b'C' | b'D' | b'E' | b'F' | b'X' | b'@' | b'P' | b'L' | b'M' | b'S'
+            | b'T',
+        ) => {
+            let ret: fn(usize) -> TerminalOutput = match csi.final_byte {
+                b'A' => TerminalOutput::CursorUp,
+                b'B' => TerminalOutput::CursorDown,
+                b'C' => TerminalOutput::CursorForward,
+                b'D' => TerminalOutput::CursorBackward,
+                b'E' => TerminalOutput::CursorNextLine,
+                b'F' => TerminalOutput::CursorPrevLine,
+                b'X' => TerminalOutput::EraseCharacters,
+                b'@' => TerminalOutput::InsertCharacters,
+                b'P' => TerminalOutput::DeleteCharacters,
+                b'L' => TerminalOutput::InsertLines,
+                b'M' => TerminalOutput::DeleteLines,
+                b'S' => TerminalOutput::ScrollUp,
+                _ => TerminalOutput::ScrollDown,
+            };
+            emit(ret(csi.param(0).unwrap_or(1).max(1)));
+        }
+        (None, b"", b'K') => {
+            let ret = match csi.param(0).unwrap_or(0) {
+                0 => TerminalOutput::ClearLineForwards,
+                1 => TerminalOutput::ClearLineBackwards,
+                2 => TerminalOutput::ClearLine,
+                param => {
+                    unhandled::record("parser", "CSI K", param);
+                    TerminalOutput::Invalid
+                }
+            };
+            emit(ret);
+        }
+        (None, b"", b'r') => {
+            emit(TerminalOutput::SetScrollRegion {
+                top: csi.param(0).unwrap_or(1).max(1),
+                bottom: csi.param(1).filter(|bottom| *bottom > 0),
+            });
+        }
+        (None, b"", b's') => emit(TerminalOutput::SaveCursor),
+        (None, b"", b'u') => emit(TerminalOutput::RestoreCursor),
+        (None, b"", b'G') => {
+            let x_pos = csi.param(0).unwrap_or(1);
 
-        match &mut self.state {
-            CsiParserState::Params => {
-                if is_csi_param(b) {
-                    self.params.push(b);
-                } else if is_csi_intermediate(b) {
-                    self.intermediates.push(b);
-                    self.state = CsiParserState::Intermediates;
-                } else if is_csi_terminator(b) {
-                    self.state = CsiParserState::Finished(b);
-                } else {
-                    self.state = CsiParserState::Invalid
+            emit(TerminalOutput::SetCursorPos {
+                x: Some(x_pos),
+                y: None,
+            });
+        }
+        (None, b"", b'J') => {
+            let ret = match csi.param(0).unwrap_or(0) {
+                0 => TerminalOutput::ClearForwards,
+                1 => TerminalOutput::ClearBackwards,
+                2 | 3 => TerminalOutput::ClearAll,
+                _ => {
+                    log::debug!(target: "parser", "Invalid clear command");
+                    TerminalOutput::Invalid
                 }
+            };
+            emit(ret);
+        }
+        (None, b"", b'm') => {
+            // A lone empty parameter means reset, other empty ones are skipped
+            if csi.param(0).is_none() && csi.params.len() <= 1 {
+                emit(TerminalOutput::Sgr(SelectGraphicRendition::Reset));
+                return;
             }
-            CsiParserState::Intermediates => {
-                if is_csi_param(b) {
-                    self.state = CsiParserState::Invalid;
-                } else if is_csi_intermediate(b) {
-                    self.intermediates.push(b);
-                } else if is_csi_terminator(b) {
-                    self.state = CsiParserState::Finished(b);
+
+            parse_sgr(&csi.params, emit);
+        }
+        (None | Some(b'?'), b"", b'n') => {
+            let ret = match (marker, csi.param(0)) {
+                (None, Some(5)) => TerminalOutput::RequestStatusReport,
+                (None, Some(6)) => TerminalOutput::RequestCursorPosition { extended: false },
+                (Some(b'?'), Some(6)) => TerminalOutput::RequestCursorPosition { extended: true },
+                (marker, param) => {
+                    let kind = if marker.is_some() { "CSI ?n" } else { "CSI n" };
+                    unhandled::record("parser", kind, param.unwrap_or(0));
+                    TerminalOutput::Invalid
+                }
+            };
+            emit(ret);
+        }
+        (None | Some(b'>'), b"", b'c') => {
+            let ret = match (marker, csi.param(0)) {
+                (None, None | Some(0)) => TerminalOutput::RequestDeviceAttributes,
+                (Some(b'>'), None | Some(0)) => TerminalOutput::RequestSecondaryDeviceAttributes,
+                (marker, param) => {
+                    let kind = if marker.is_some() { "CSI >c" } else { "CSI c" };
+                    unhandled::record("parser", kind, param.unwrap_or(0));
+                    TerminalOutput::Invalid
+                }
+            };
+            emit(ret);
+        }
+        (Some(b'>'), b"", b'q') => {
+            let ret = match csi.param(0) {
+                None | Some(0) => TerminalOutput::RequestTerminalVersion,
+                _ => {
+                    log::debug!(target: "parser", "Invalid terminal version request");
+                    TerminalOutput::Invalid
+                }
+            };
+            emit(ret);
+        }
+        (Some(b'?'), b"", b'h' | b'l') => {
+            for param in csi.params.iter().filter_map(|param| param.value) {
+                let mode = DecMode::from_usize(param);
+                if csi.final_byte == b'h' {
+                    emit(TerminalOutput::SetMode(mode));
                 } else {
-                    self.state = CsiParserState::Invalid
+                    emit(TerminalOutput::ResetMode(mode));
                 }
             }
-            CsiParserState::Invalid => {
-                if is_csi_terminator(b) {
-                    self.state = CsiParserState::InvalidFinished;
+        }
+        (None, b"", b'g') => {
+            let ret = match csi.param(0) {
+                None | Some(0) => TerminalOutput::ClearTabStop,
+                Some(3) => TerminalOutput::ClearAllTabStops,
+                Some(param) => {
+                    unhandled::record("parser", "CSI g", param);
+                    TerminalOutput::Invalid
                 }
+            };
+            emit(ret);
+        }
+        (None, b"", b'I' | b'Z') => {
+            let count = csi.param(0).unwrap_or(1).max(1);
+            if csi.final_byte == b'I' {
+                emit(TerminalOutput::ForwardTab(count));
+            } else {
+                emit(TerminalOutput::BackwardTab(count));
             }
-            CsiParserState::Finished(_) | CsiParserState::InvalidFinished => {
-                unreachable!();
+        }
+        (None, b"", b'b') | (None, b" ", b'@' | b'A') | (None, b"'", b'}' | b'~') => {
+            let ret: fn(usize) -> TerminalOutput = match (&csi.intermediates[..], csi.final_byte) {
+                (b"", b'b') => TerminalOutput::RepeatCharacter,
+                (b" ", b'@') => TerminalOutput::ScrollLeft,
+                (b" ", b'A') => TerminalOutput::ScrollRight,
+                (b"'", b'}') => TerminalOutput::InsertColumns,
+                _ => TerminalOutput::DeleteColumns,
+            };
+            emit(ret(csi.param(0).unwrap_or(1).max(1)));
+        }
+        (None, b" ", b'q') => {
+            let Some(style) = CursorStyle::from_decscusr(csi.param(0).unwrap_or(0)) else {
+                log::debug!(target: "parser", "Invalid cursor style");
+                emit(TerminalOutput::Invalid);
+                return;
+            };
+
+            emit(TerminalOutput::SetCursorStyle(style));
+        }
+        (None, b"!", b'p') => emit(TerminalOutput::SoftReset),
+        (Some(b'?'), b"$", b'p') => {
+            let Some(mode) = csi.param(0) else {
+                log::debug!(target: "parser", "Invalid mode request");
+                emit(TerminalOutput::Invalid);
+                return;
+            };
+
+            emit(TerminalOutput::RequestMode(DecMode::from_usize(mode)));
+        }
+        _ => {
+            record_unhandled_csi(csi);
+            emit(TerminalOutput::Invalid);
+        }
+    }
+}
+
+/// Turns an escape sequence other than CSI, OSC or DCS into the output it stands for
+pub fn dispatch_esc(intermediates: &[u8], final_byte: u8) -> TerminalOutput {
+    match (intermediates, final_byte) {
+        (b"", b'H') => TerminalOutput::SetTabStop,
+        (b"", b'c') => TerminalOutput::FullReset,
+        (b"", b'D') => TerminalOutput::Index,
+        (b"", b'E') => TerminalOutput::NextLine,
+        (b"", b'M') => TerminalOutput::ReverseIndex,
+        (b"", b'7') => TerminalOutput::SaveCursor,
+        (b"", b'8') => TerminalOutput::RestoreCursor,
+        (b"", b'N') => TerminalOutput::SingleShift(2),
+        (b"", b'O') => TerminalOutput::SingleShift(3),
+        (b"", b'n') => TerminalOutput::LockingShift(2),
+        (b"", b'o') => TerminalOutput::LockingShift(3),
+        (&[slot @ b'('..=b'+'], designator) => match Charset::from_designator(designator) {
+            Some(charset) => TerminalOutput::DesignateCharset {
+                slot: (slot - b'(') as usize,
+                charset,
+            },
+            None => {
+                unhandled::record_bytes("parser", "ESC", [slot, designator]);
+                TerminalOutput::Invalid
             }
+        },
+        (b"#", b'8') => TerminalOutput::ScreenAlignmentTest,
+        _ => {
+            let bytes = intermediates.iter().copied().chain([final_byte]);
+            unhandled::record_bytes("parser", "ESC", bytes);
+            TerminalOutput::Invalid
         }
     }
 }
 
-enum AnsiParserInner {
-    Empty,
+/// Receives the pieces of the output stream as the parser recognizes them. Slices borrow from the
+/// input or the parser's own buffers, so nothing is allocated per sequence
+pub trait Perform {
+    /// A run of printable bytes, which may stop in the middle of a utf8 sequence
+    fn print(&mut self, data: &[u8]);
+    /// A C0 control other than ESC, or a C1 control that doesn't start a sequence
+    fn execute(&mut self, byte: u8);
+    /// A complete CSI sequence. ignore is set if it was malformed, in which case the parameters
+    /// and intermediates may be incomplete
+    fn csi_dispatch(&mut self, csi: &Csi, ignore: bool);
+    fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8);
+    /// Contents of an OSC string, without the terminator
+    fn osc_dispatch(&mut self, data: &[u8]);
+    /// Start of a DCS string, the contents follow through put and unhook marks the end
+    fn hook(&mut self, _header: &Csi) {}
+    fn put(&mut self, _data: &[u8]) {}
+    fn unhook(&mut self) {}
+}
+
+/// Collects parser callbacks into TerminalOutput values for AnsiParser::push
+#[derive(Default)]
+struct OutputCollector {
+    output: Vec<TerminalOutput>,
+    data: Vec<u8>,
+}
+
+impl OutputCollector {
+    fn flush_data(&mut self) {
+        if !self.data.is_empty() {
+            self.output
+                .push(TerminalOutput::Data(std::mem::take(&mut self.data)));
+        }
+    }
+}
+
+impl Perform for OutputCollector {
+    fn print(&mut self, data: &[u8]) {
+        self.data.extend_from_slice(data);
+    }
+
+    fn execute(&mut self, byte: u8) {
+        let shift = match byte {
+            // SO and SI
+            0x0e => TerminalOutput::LockingShift(1),
+            0x0f => TerminalOutput::LockingShift(0),
+            0x80..=0x9f => {
+                self.flush_data();
+                self.output.push(dispatch_esc(&[], byte - 0x40));
+                return;
+            }
+            // Everything else is left for whoever handles the data
+            _ => {
+                self.data.push(byte);
+                return;
+            }
+        };
+        self.flush_data();
+        self.output.push(shift);
+    }
+
+    fn csi_dispatch(&mut self, csi: &Csi, ignore: bool) {
+        self.flush_data();
+        let output = &mut self.output;
+        dispatch_csi(csi, ignore, &mut |ret| output.push(ret));
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8) {
+        self.flush_data();
+        self.output.push(dispatch_esc(intermediates, final_byte));
+    }
+
+    fn osc_dispatch(&mut self, data: &[u8]) {
+        self.flush_data();
+        let output = &mut self.output;
+        dispatch_osc(data, &mut |ret| output.push(ret));
+    }
+}
+
+/// Most intermediates a sequence can have before it is ignored, the DEC parser only keeps two
+const MAX_INTERMEDIATES: usize = 2;
+/// OSC strings longer than this are dropped rather than buffered without bound, xterm stops
+/// somewhere between 4 and 64 KiB depending on the build
+const MAX_OSC_LEN: usize = 64 * 1024;
+
+/// States of the DEC ANSI parser, see https://vt100.net/emu/dec_ansi_parser
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum State {
+    Ground,
     Escape,
-    Csi(CsiParser),
+    EscapeIntermediate,
+    CsiEntry,
+    CsiParam,
+    CsiIntermediate,
+    CsiIgnore,
+    DcsEntry,
+    DcsParam,
+    DcsIntermediate,
+    DcsPassthrough,
+    DcsIgnore,
+    OscString,
+    SosPmApcString,
 }
 
 pub struct AnsiParser {
-    inner: AnsiParserInner,
+    state: State,
+    /// The sequence being parsed, kept around so its buffers can be reused. Escape sequences
+    /// only use the intermediates
+    csi: Csi,
+    /// Set once the current sequence has too many parameters or intermediates to be understood, or
+    /// an OSC string runs past MAX_OSC_LEN
+    ignore: bool,
+    osc: Vec<u8>,
+    /// Continuation bytes still expected for the utf8 sequence being passed through. Bytes in
+    /// 0x80..=0x9f are only C1 controls when they are not part of one
+    utf8_remaining: u8,
+}
+
+impl Default for AnsiParser {
+    fn default() -> AnsiParser {
+        AnsiParser::new()
+    }
 }
 
 impl AnsiParser {
     pub fn new() -> AnsiParser {
         AnsiParser {
-            inner: AnsiParserInner::Empty,
+            state: State::Ground,
+            csi: Csi::default(),
+            ignore: false,
+            osc: Vec::new(),
+            utf8_remaining: 0,
         }
     }
 
-    pub fn push(&mut self, incoming: &[u8]) -> Vec<TerminalOutput> {
-        let mut output = Vec::new();
-        let mut data_output = Vec::new();
-        for b in incoming {
-            match &mut self.inner {
-                AnsiParserInner::Empty => {
-                    if *b == b'\x1b' {
-                        self.inner = AnsiParserInner::Escape;
-                        continue;
-                    }
-
-                    data_output.push(*b);
+    /// Parses incoming, calling into performer for everything that is recognized. Sequences can
+    /// be split across calls
+    pub fn advance<P: Perform>(&mut self, incoming: &[u8], performer: &mut P) {
+        let mut pos = 0;
+        while pos < incoming.len() {
+            // Runs of data are handed over at once rather than byte by byte
+            let rest = &incoming[pos..];
+            let len = match self.state {
+                State::Ground => self.data_len(rest, |b| b < 0x20 || b == 0x7f),
+                State::OscString => self.data_len(rest, |b| b < 0x20),
+                State::DcsPassthrough | State::SosPmApcString => {
+                    self.data_len(rest, |b| matches!(b, 0x18 | 0x1a | 0x1b | 0x7f))
                 }
-                AnsiParserInner::Escape => {
-                    if !data_output.is_empty() {
-                        output.push(TerminalOutput::Data(std::mem::take(&mut data_output)));
-                    }
+                _ => 0,
+            };
 
-                    match b {
-                        b'[' => {
-                            self.inner = AnsiParserInner::Csi(CsiParser::new());
-                        }
-                        _ => {
-                            let b_utf8 = std::char::from_u32(*b as u32);
-                            println!("Unhandled escape sequence {b_utf8:?} {b:x}");
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                    }
+            if len > 0 {
+                let data = &rest[..len];
+                match self.state {
+                    State::Ground => performer.print(data),
+                    State::OscString => self.push_osc(data),
+                    State::DcsPassthrough => performer.put(data),
+                    _ => {}
                 }
-                AnsiParserInner::Csi(parser) => {
-                    parser.push(*b);
-                    match parser.state {
-                        CsiParserState::Finished(b'H') => {
-                            let params =
-                                split_params_into_semicolon_delimited_usize(&parser.params);
-
-                            let Ok(params) = params else {
-                                println!("Invalid cursor set position sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
-
-                            output.push(TerminalOutput::SetCursorPos {
-                                x: Some(extract_param(0, &params).unwrap_or(1)),
-                                y: Some(extract_param(1, &params).unwrap_or(1)),
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'G') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
-                                println!("Invalid cursor set position sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
-
-                            let x_pos = param.unwrap_or(1);
-
-                            output.push(TerminalOutput::SetCursorPos {
-                                x: Some(x_pos),
-                                y: None,
-                            });
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'J') => {
-                            let Ok(param) = parse_param_as_usize(&parser.params) else {
-                                println!("Invalid clear command");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
-
-                            let ret = match param.unwrap_or(0) {
-                                0 => TerminalOutput::ClearForwards,
-                                1 => TerminalOutput::ClearBackwards,
-                                2 | 3 => TerminalOutput::ClearAll,
-                                _ => TerminalOutput::Invalid,
-                            };
-                            output.push(ret);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(b'm') => {
-                            let params =
-                                split_params_into_semicolon_delimited_usize(&parser.params);
-
-                            let Ok(mut params) = params else {
-                                println!("Invalid SGR sequence");
-                                output.push(TerminalOutput::Invalid);
-                                self.inner = AnsiParserInner::Empty;
-                                continue;
-                            };
-
-                            if params.is_empty() {
-                                params.push(Some(0));
-                            }
-
-                            if params.len() == 1 && params[0].is_none() {
-                                params[0] = Some(0);
-                            }
-
-                            for param in params {
-                                let Some(param) = param else {
-                                    continue;
-                                };
-                                output.push(TerminalOutput::Sgr(
-                                    SelectGraphicRendition::from_usize(param),
-                                ));
-                            }
-
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Finished(esc) => {
-                            println!(
-                                "Unhandled csi code: {:?} {esc:x}",
-                                std::char::from_u32(esc as u32)
-                            );
-                            output.push(TerminalOutput::Invalid);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        CsiParserState::Invalid => {
-                            println!("Invalid CSI sequence");
-                            output.push(TerminalOutput::Invalid);
-                            self.inner = AnsiParserInner::Empty;
-                        }
-                        _ => {}
-                    }
+                pos += len;
+                continue;
+            }
+
+            self.advance_byte(incoming[pos], performer);
+            pos += 1;
+        }
+    }
+
+    /// Length of the data at the start of incoming, which ends at a byte matching is_stop or a C1
+    /// control
+    fn data_len(&mut self, incoming: &[u8], is_stop: impl Fn(u8) -> bool) -> usize {
+        for (i, &b) in incoming.iter().enumerate() {
+            match b {
+                0x80..=0xbf if self.utf8_remaining > 0 => {
+                    self.utf8_remaining -= 1;
+                    continue;
                 }
+                0x80..=0x9f => return i,
+                _ if is_stop(b) => return i,
+                _ => {}
             }
+
+            // Invalid utf8 is passed on as is, the decoder turns it into replacement characters
+            self.utf8_remaining = match b {
+                0xc2..=0xdf => 1,
+                0xe0..=0xef => 2,
+                0xf0..=0xf4 => 3,
+                _ => 0,
+            };
+        }
+
+        incoming.len()
+    }
+
+    fn clear(&mut self) {
+        self.csi.clear();
+        self.ignore = false;
+    }
+
+    fn collect(&mut self, b: u8) {
+        if self.csi.intermediates.len() < MAX_INTERMEDIATES {
+            self.csi.intermediates.push(b);
+        } else {
+            self.ignore = true;
+        }
+    }
+
+    fn param(&mut self, b: u8) {
+        if !self.csi.push_param_byte(b) {
+            self.ignore = true;
         }
+    }
 
-        if !data_output.is_empty() {
-            output.push(TerminalOutput::Data(data_output));
+    fn push_osc(&mut self, data: &[u8]) {
+        if self.ignore {
+            return;
         }
+        if self.osc.len() + data.len() > MAX_OSC_LEN {
+            log::debug!(target: "parser", "Ignoring osc longer than {MAX_OSC_LEN} bytes");
+            self.ignore = true;
+            self.osc = Vec::new();
+            return;
+        }
+        self.osc.extend_from_slice(data);
+    }
+
+    /// Leaves a string state, dispatching what was collected unless the string was cancelled or
+    /// too long
+    fn end_string<P: Perform>(&mut self, cancelled: bool, performer: &mut P) {
+        match self.state {
+            State::OscString if !cancelled && !self.ignore => performer.osc_dispatch(&self.osc),
+            State::DcsPassthrough => performer.unhook(),
+            _ => {}
+        }
+    }
 
-        output
+    fn csi_dispatch<P: Perform>(&mut self, ignore: bool, final_byte: u8, performer: &mut P) {
+        let ignore = ignore || self.ignore;
+        self.csi.final_byte = final_byte;
+        performer.csi_dispatch(&self.csi, ignore);
+        self.state = State::Ground;
+    }
+
+    fn hook<P: Perform>(&mut self, final_byte: u8, performer: &mut P) {
+        if self.ignore {
+            self.state = State::DcsIgnore;
+            return;
+        }
+        self.csi.final_byte = final_byte;
+        performer.hook(&self.csi);
+        self.state = State::DcsPassthrough;
+    }
+
+    fn advance_byte<P: Perform>(&mut self, b: u8, performer: &mut P) {
+        // Anything but data interrupts a utf8 sequence
+        self.utf8_remaining = 0;
+
+        // Transitions that apply in every state
+        match b {
+            // CAN and SUB abort the current sequence
+            0x18 | 0x1a => {
+                self.end_string(true, performer);
+                performer.execute(b);
+                self.state = State::Ground;
+                return;
+            }
+            0x1b => {
+                self.end_string(false, performer);
+                self.clear();
+                self.state = State::Escape;
+                return;
+            }
+            0x80..=0x9f => {
+                self.end_string(false, performer);
+                self.advance_c1(b, performer);
+                return;
+            }
+            _ => {}
+        }
+
+        match (self.state, b) {
+            (State::Ground, 0x00..=0x1f) => performer.execute(b),
+            (State::Ground, _) => {}
+
+            (State::Escape | State::EscapeIntermediate, 0x00..=0x1f) => performer.execute(b),
+            (State::Escape | State::EscapeIntermediate, 0x20..=0x2f) => {
+                self.collect(b);
+                self.state = State::EscapeIntermediate;
+            }
+            (State::Escape, b'[') => self.state = State::CsiEntry,
+            (State::Escape, b']') => {
+                self.osc.clear();
+                self.state = State::OscString;
+            }
+            (State::Escape, b'P') => self.state = State::DcsEntry,
+            (State::Escape, b'X' | b'^' | b'_') => self.state = State::SosPmApcString,
+            // ST without a string to terminate
+            (State::Escape, b'\\') => self.state = State::Ground,
+            (State::Escape | State::EscapeIntermediate, 0x30..=0x7e) => {
+                if !self.ignore {
+                    performer.esc_dispatch(&self.csi.intermediates, b);
+                }
+                self.state = State::Ground;
+            }
+            (State::Escape | State::EscapeIntermediate, _) => {}
+
+            (
+                State::CsiEntry | State::CsiParam | State::CsiIntermediate | State::CsiIgnore,
+                0x00..=0x1f,
+            ) => performer.execute(b),
+            (State::CsiEntry | State::CsiParam | State::CsiIntermediate, 0x20..=0x2f) => {
+                self.collect(b);
+                self.state = State::CsiIntermediate;
+            }
+            (State::CsiEntry, 0x3c..=0x3f) => {
+                self.csi.private_marker = Some(b);
+                self.state = State::CsiParam;
+            }
+            (State::CsiEntry | State::CsiParam, 0x30..=0x3b) => {
+                self.param(b);
+                self.state = State::CsiParam;
+            }
+            // Private markers are only valid at the start
+            (State::CsiParam | State::CsiIntermediate, 0x30..=0x3f) => {
+                self.state = State::CsiIgnore
+            }
+            (State::CsiEntry | State::CsiParam | State::CsiIntermediate, 0x40..=0x7e) => {
+                self.csi_dispatch(false, b, performer)
+            }
+            (State::CsiIgnore, 0x40..=0x7e) => self.csi_dispatch(true, b, performer),
+            (State::CsiEntry | State::CsiParam | State::CsiIntermediate | State::CsiIgnore, _) => {}
+
+            (State::DcsEntry | State::DcsParam | State::DcsIntermediate, 0x20..=0x2f) => {
+                self.collect(b);
+                self.state = State::DcsIntermediate;
+            }
+            (State::DcsEntry, 0x3c..=0x3f) => {
+                self.csi.private_marker = Some(b);
+                self.state = State::DcsParam;
+            }
+            (State::DcsEntry | State::DcsParam, 0x30..=0x3b) => {
+                self.param(b);
+                self.state = State::DcsParam;
+            }
+            (State::DcsParam | State::DcsIntermediate, 0x30..=0x3f) => {
+                self.state = State::DcsIgnore
+            }
+            (State::DcsEntry | State::DcsParam | State::DcsIntermediate, 0x40..=0x7e) => {
+                self.hook(b, performer)
+            }
+            (State::DcsEntry | State::DcsParam | State::DcsIntermediate, _) => {}
+
+            // Data runs are handled in advance, so only ignored bytes get here
+            (State::DcsPassthrough | State::DcsIgnore | State::SosPmApcString, _) => {}
+
+            // BEL is the xterm specific terminator, ESC \ and ST are the standard ones
+            (State::OscString, 0x07) => {
+                self.end_string(false, performer);
+                self.state = State::Ground;
+            }
+            (State::OscString, _) => {}
+        }
+    }
+
+    /// 8 bit controls, which stand for ESC followed by b - 0x40
+    fn advance_c1<P: Perform>(&mut self, b: u8, performer: &mut P) {
+        self.clear();
+        self.state = match b {
+            0x90 => State::DcsEntry,
+            0x9b => State::CsiEntry,
+            0x9c => State::Ground,
+            0x9d => {
+                self.osc.clear();
+                State::OscString
+            }
+            0x98 | 0x9e | 0x9f => State::SosPmApcString,
+            _ => {
+                performer.execute(b);
+                State::Ground
+            }
+        };
+    }
+
+    /// Parses incoming into a list of outputs, for callers that don't need to avoid allocating
+    pub fn push(&mut self, incoming: &[u8]) -> Vec<TerminalOutput> {
+        let mut collector = OutputCollector::default();
+        self.advance(incoming, &mut collector);
+        collector.flush_data();
+        collector.output
     }
 }
 
@@ -326,8 +1111,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(32),
-                y: Some(15)
+                x: Some(15),
+                y: Some(32)
             }
         ));
 
@@ -336,8 +1121,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(1),
-                y: Some(32)
+                x: Some(32),
+                y: Some(1)
             }
         ));
 
@@ -346,8 +1131,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(32),
-                y: Some(1)
+                x: Some(1),
+                y: Some(32)
             }
         ));
 
@@ -356,8 +1141,8 @@ mod test {
         assert!(matches!(
             parsed[0],
             TerminalOutput::SetCursorPos {
-                x: Some(32),
-                y: Some(1)
+                x: Some(1),
+                y: Some(32)
             }
         ));
 
@@ -425,27 +1210,37 @@ mod test {
     }
 
     #[test]
-    fn test_parsing_unknown_csi() {
-        let mut parser = CsiParser::new();
-        for b in b"0123456789:;<=>?!\"#$%&'()*+,-./}" {
-            parser.push(*b);
-        }
+    fn test_csi_params() {
+        let mut output_buffer = AnsiParser::new();
 
-        assert_eq!(parser.params, b"0123456789:;<=>?");
-        assert_eq!(parser.intermediates, b"!\"#$%&'()*+,-./");
-        assert!(matches!(parser.state, CsiParserState::Finished(b'}')));
-    }
+        // Values that don't fit are clamped rather than rejected
+        assert_eq!(
+            output_buffer.push(b"\x1b[99999999999999999999999G"),
+            &[TerminalOutput::SetCursorPos {
+                x: Some(65535),
+                y: None
+            }]
+        );
 
-    #[test]
-    fn test_parsing_invalid_csi() {
-        let mut parser = CsiParser::new();
-        for b in b"0$0" {
-            parser.push(*b);
-        }
+        // Sub-parameters don't get in the way of the attributes we know
+        assert_eq!(
+            output_buffer.push(b"\x1b[1;4:3;31m"),
+            &[
+                TerminalOutput::Sgr(SelectGraphicRendition::Bold),
+                TerminalOutput::Sgr(SelectGraphicRendition::Unknown(4)),
+                TerminalOutput::Sgr(SelectGraphicRendition::Red),
+            ]
+        );
+
+        // A private marker makes it a different sequence
+        assert_eq!(output_buffer.push(b"\x1b[?2J"), &[TerminalOutput::Invalid]);
+        assert_eq!(output_buffer.push(b"\x1b[>1m"), &[TerminalOutput::Invalid]);
 
-        assert!(matches!(parser.state, CsiParserState::Invalid));
-        parser.push(b'm');
-        assert!(matches!(parser.state, CsiParserState::InvalidFinished));
+        let too_many = format!("\x1b[{}m", "1;".repeat(MAX_PARAMS));
+        assert_eq!(
+            output_buffer.push(too_many.as_bytes()),
+            &[TerminalOutput::Invalid]
+        );
     }
 
     #[test]
@@ -458,6 +1253,44 @@ mod test {
         ));
     }
 
+    #[test]
+    fn test_extended_color_sgr() {
+        use SelectGraphicRendition::*;
+
+        let cases: &[(&[u8], &[SelectGraphicRendition])] = &[
+            (b"\x1b[38;5;1m", &[Red]),
+            (b"\x1b[38;5;9m", &[BrightRed]),
+            (b"\x1b[38;5;31m", &[Unknown(38)]),
+            (b"\x1b[1;38;5;0;32m", &[Bold, Black, Green]),
+            // The zeros are color components, not resets
+            (b"\x1b[38;2;0;0;0;1m", &[Unknown(38), Bold]),
+            (b"\x1b[48;5;1;32m", &[Unknown(48), Green]),
+            (b"\x1b[48;2;255;0;0m", &[Unknown(48)]),
+            (b"\x1b[58;5;1m", &[Unknown(58)]),
+            (b"\x1b[38:5:1m", &[Red]),
+            (b"\x1b[38:2:0:0:0;1m", &[Unknown(38), Bold]),
+            (b"\x1b[38:2::255:0:0;1m", &[Unknown(38), Bold]),
+            (b"\x1b[48:5:0m", &[Unknown(48)]),
+            // Missing arguments only drop the color
+            (b"\x1b[38;5m", &[Unknown(38)]),
+            (b"\x1b[38m", &[Unknown(38)]),
+        ];
+
+        let mut output_buffer = AnsiParser::new();
+        for &(input, expected) in cases {
+            let expected: Vec<_> = expected
+                .iter()
+                .map(|&sgr| TerminalOutput::Sgr(sgr))
+                .collect();
+            assert_eq!(
+                output_buffer.push(input),
+                expected,
+                "{}",
+                String::from_utf8_lossy(input)
+            );
+        }
+    }
+
     #[test]
     fn test_color_parsing() {
         let mut output_buffer = AnsiParser::new();
@@ -520,4 +1353,642 @@ mod test {
             ]
         );
     }
+
+    #[test]
+    fn test_osc_palette() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b]4;1;rgb:ff/80/00;2;?\x07");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetPaletteColor {
+                    index: 1,
+                    color: Rgb::new(255, 128, 0)
+                },
+                TerminalOutput::QueryPaletteColor(2),
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x1b]4;256;?\x07");
+        assert_eq!(parsed, &[TerminalOutput::Invalid]);
+
+        let parsed = output_buffer.push(b"\x1b]104\x07\x1b]104;3;5\x1b\\");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::ResetPaletteColor(None),
+                TerminalOutput::ResetPaletteColor(Some(3)),
+                TerminalOutput::ResetPaletteColor(Some(5)),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_dynamic_colors() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b]11;?\x1b\\");
+        assert_eq!(
+            parsed,
+            &[TerminalOutput::QueryDynamicColor(DynamicColor::Background)]
+        );
+
+        let parsed = output_buffer.push(b"\x1b]10;#ffffff;?;?\x07");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetDynamicColor {
+                    target: DynamicColor::Foreground,
+                    color: Rgb::new(255, 255, 255)
+                },
+                TerminalOutput::QueryDynamicColor(DynamicColor::Background),
+                TerminalOutput::QueryDynamicColor(DynamicColor::Cursor),
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x1b]110\x07\x1b]111\x07\x1b]112\x07");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::ResetDynamicColor(DynamicColor::Foreground),
+                TerminalOutput::ResetDynamicColor(DynamicColor::Background),
+                TerminalOutput::ResetDynamicColor(DynamicColor::Cursor),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_osc_split_across_pushes() {
+        let mut output_buffer = AnsiParser::new();
+        assert!(output_buffer.push(b"a\x1b]11;").len() == 1);
+        assert!(output_buffer.push(b"?").is_empty());
+        // The ESC of the terminator already ends the string
+        assert_eq!(
+            output_buffer.push(b"\x1b"),
+            &[TerminalOutput::QueryDynamicColor(DynamicColor::Background)]
+        );
+        assert_eq!(
+            output_buffer.push(b"\\b"),
+            &[TerminalOutput::Data(b"b".into())]
+        );
+    }
+
+    #[test]
+    fn test_osc_too_long() {
+        let mut output_buffer = AnsiParser::new();
+        assert!(output_buffer.push(b"\x1b]11;").is_empty());
+        for _ in 0..MAX_OSC_LEN / 1024 {
+            assert!(output_buffer.push(&[b'a'; 1024]).is_empty());
+        }
+        assert!(output_buffer.push(b"?\x07").is_empty());
+        assert!(output_buffer.osc.capacity() <= MAX_OSC_LEN);
+
+        // Only the string that ran over is dropped
+        assert_eq!(
+            output_buffer.push(b"\x1b]11;?\x07"),
+            &[TerminalOutput::QueryDynamicColor(DynamicColor::Background)]
+        );
+    }
+
+    #[test]
+    fn test_device_reports() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[5n\x1b[6n\x1b[?6n\x1b[7n");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RequestStatusReport,
+                TerminalOutput::RequestCursorPosition { extended: false },
+                TerminalOutput::RequestCursorPosition { extended: true },
+                TerminalOutput::Invalid,
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x1b[c\x1b[0c\x1b[>c\x1b[>0c\x1b[=c");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RequestDeviceAttributes,
+                TerminalOutput::RequestDeviceAttributes,
+                TerminalOutput::RequestSecondaryDeviceAttributes,
+                TerminalOutput::RequestSecondaryDeviceAttributes,
+                TerminalOutput::Invalid,
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x1b[>q\x1b[>0q\x1b[>1q");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RequestTerminalVersion,
+                TerminalOutput::RequestTerminalVersion,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_dec_modes() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[?1000;1006h\x1b[?1003l\x1b[?7777h");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetMode(DecMode::NormalMouse),
+                TerminalOutput::SetMode(DecMode::SgrMouse),
+                TerminalOutput::ResetMode(DecMode::AnyEventMouse),
+                TerminalOutput::SetMode(DecMode::Unknown(7777)),
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x1b[4h");
+        assert_eq!(parsed, &[TerminalOutput::Invalid]);
+
+        let parsed = output_buffer.push(b"\x1b[?2026$p\x1b[?5$p\x1b[2026$p");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RequestMode(DecMode::SynchronizedUpdate),
+                TerminalOutput::RequestMode(DecMode::Unknown(5)),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_style() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[ q\x1b[2 q\x1b[3 q\x1b[6 q\x1b[7 q\x1b[?25l");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetCursorStyle(CursorStyle {
+                    shape: CursorShape::Block,
+                    blinking: true
+                }),
+                TerminalOutput::SetCursorStyle(CursorStyle {
+                    shape: CursorShape::Block,
+                    blinking: false
+                }),
+                TerminalOutput::SetCursorStyle(CursorStyle {
+                    shape: CursorShape::Underline,
+                    blinking: true
+                }),
+                TerminalOutput::SetCursorStyle(CursorStyle {
+                    shape: CursorShape::Bar,
+                    blinking: false
+                }),
+                TerminalOutput::Invalid,
+                TerminalOutput::ResetMode(DecMode::ShowCursor),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_charset_sequences() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b(0lq\x1b(B\x1b)0a\x0eb\x0f\x1bN\x1bo\x1b(Z");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::DesignateCharset {
+                    slot: 0,
+                    charset: Charset::DecSpecialGraphics
+                },
+                TerminalOutput::Data(b"lq".into()),
+                TerminalOutput::DesignateCharset {
+                    slot: 0,
+                    charset: Charset::Ascii
+                },
+                TerminalOutput::DesignateCharset {
+                    slot: 1,
+                    charset: Charset::DecSpecialGraphics
+                },
+                TerminalOutput::Data(b"a".into()),
+                TerminalOutput::LockingShift(1),
+                TerminalOutput::Data(b"b".into()),
+                TerminalOutput::LockingShift(0),
+                TerminalOutput::SingleShift(2),
+                TerminalOutput::LockingShift(3),
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_tab_stop_sequences() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed =
+            output_buffer.push(b"\x1bH\x1b[g\x1b[0g\x1b[3g\x1b[2g\x1b[I\x1b[3I\x1b[Z\x1b[0Z");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetTabStop,
+                TerminalOutput::ClearTabStop,
+                TerminalOutput::ClearTabStop,
+                TerminalOutput::ClearAllTabStops,
+                TerminalOutput::Invalid,
+                TerminalOutput::ForwardTab(1),
+                TerminalOutput::ForwardTab(3),
+                TerminalOutput::BackwardTab(1),
+                TerminalOutput::BackwardTab(1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_cursor_and_line_sequences() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[A\x1b[0B\x1b[3C\x1b[2f\x1b[5d\x1b[K\x1b[1K\x1b[3K");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::CursorUp(1),
+                TerminalOutput::CursorDown(1),
+                TerminalOutput::CursorForward(3),
+                TerminalOutput::SetCursorPos {
+                    x: Some(1),
+                    y: Some(2)
+                },
+                TerminalOutput::SetCursorPos {
+                    x: None,
+                    y: Some(5)
+                },
+                TerminalOutput::ClearLineForwards,
+                TerminalOutput::ClearLineBackwards,
+                TerminalOutput::Invalid,
+            ]
+        );
+
+        let parsed = output_buffer.push(b"\x1b[r\x1b[2;0r\x1b[3;9r\x1bD\x1bM\x1b7\x1b[u");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::SetScrollRegion {
+                    top: 1,
+                    bottom: None
+                },
+                TerminalOutput::SetScrollRegion {
+                    top: 2,
+                    bottom: None
+                },
+                TerminalOutput::SetScrollRegion {
+                    top: 3,
+                    bottom: Some(9)
+                },
+                TerminalOutput::Index,
+                TerminalOutput::ReverseIndex,
+                TerminalOutput::SaveCursor,
+                TerminalOutput::RestoreCursor,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_reset_sequences() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"a\x1bcb\x1b[!p\x1b#8\x1b#9");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::Data(b"a".to_vec()),
+                TerminalOutput::FullReset,
+                TerminalOutput::Data(b"b".to_vec()),
+                TerminalOutput::SoftReset,
+                TerminalOutput::ScreenAlignmentTest,
+                TerminalOutput::Invalid,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_repeat_and_column_sequences() {
+        let mut output_buffer = AnsiParser::new();
+        let parsed = output_buffer.push(b"\x1b[b\x1b[3b\x1b[2 @\x1b[ A\x1b[4'}\x1b[0'~\x1b[@");
+        assert_eq!(
+            parsed,
+            &[
+                TerminalOutput::RepeatCharacter(1),
+                TerminalOutput::RepeatCharacter(3),
+                TerminalOutput::ScrollLeft(2),
+                TerminalOutput::ScrollRight(1),
+                TerminalOutput::InsertColumns(4),
+                TerminalOutput::DeleteColumns(1),
+                TerminalOutput::InsertCharacters(1),
+            ]
+        );
+    }
+
+    /// Records every callback as a readable string, joining consecutive runs of data
+    #[derive(Default)]
+    struct RecordingPerformer {
+        events: Vec<String>,
+        data_kind: &'static str,
+        data: Vec<u8>,
+    }
+
+    impl RecordingPerformer {
+        fn record_data(&mut self, kind: &'static str, data: &[u8]) {
+            if kind != self.data_kind {
+                self.flush_data();
+            }
+            self.data_kind = kind;
+            self.data.extend_from_slice(data);
+        }
+
+        fn flush_data(&mut self) {
+            if !self.data.is_empty() {
+                let data = String::from_utf8_lossy(&self.data);
+                self.events.push(format!("{} {data:?}", self.data_kind));
+                self.data.clear();
+            }
+        }
+
+        fn record(&mut self, event: String) {
+            self.flush_data();
+            self.events.push(event);
+        }
+    }
+
+    /// Writes the private marker and parameters back out the way they would be sent
+    fn format_params(csi: &Csi) -> String {
+        let format_value = |value: &Option<usize>| value.map(|v| v.to_string()).unwrap_or_default();
+        let params = csi
+            .params
+            .iter()
+            .map(|param| {
+                std::iter::once(format_value(&param.value))
+                    .chain(param.subparams.iter().map(format_value))
+                    .collect::<Vec<_>>()
+                    .join(":")
+            })
+            .collect::<Vec<_>>()
+            .join(";");
+        let marker = csi
+            .private_marker
+            .map(|marker| char::from(marker).to_string())
+            .unwrap_or_default();
+        format!("{marker}{params}")
+    }
+
+    impl Perform for RecordingPerformer {
+        fn print(&mut self, data: &[u8]) {
+            self.record_data("print", data);
+        }
+
+        fn execute(&mut self, byte: u8) {
+            self.record(format!("execute {byte:#x}"));
+        }
+
+        fn csi_dispatch(&mut self, csi: &Csi, ignore: bool) {
+            self.record(format!(
+                "csi {:?} {:?} {ignore} {:?}",
+                format_params(csi),
+                String::from_utf8_lossy(&csi.intermediates),
+                csi.final_byte as char
+            ));
+        }
+
+        fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8) {
+            self.record(format!(
+                "esc {:?} {:?}",
+                String::from_utf8_lossy(intermediates),
+                final_byte as char
+            ));
+        }
+
+        fn osc_dispatch(&mut self, data: &[u8]) {
+            self.record(format!("osc {:?}", String::from_utf8_lossy(data)));
+        }
+
+        fn hook(&mut self, header: &Csi) {
+            self.record(format!(
+                "hook {:?} {:?} {:?}",
+                format_params(header),
+                String::from_utf8_lossy(&header.intermediates),
+                header.final_byte as char
+            ));
+        }
+
+        fn put(&mut self, data: &[u8]) {
+            self.record_data("put", data);
+        }
+
+        fn unhook(&mut self) {
+            self.record("unhook".to_string());
+        }
+    }
+
+    fn record<'a>(chunks: impl IntoIterator<Item = &'a [u8]>) -> Vec<String> {
+        let mut parser = AnsiParser::new();
+        let mut performer = RecordingPerformer::default();
+        for chunk in chunks {
+            parser.advance(chunk, &mut performer);
+        }
+        performer.flush_data();
+        performer.events
+    }
+
+    #[test]
+    fn test_perform() {
+        assert_eq!(
+            record([&b"hello\r\n\x1b[1;2 qworld\x1b#8\x1b]0;title\x07\x1bP1$qm\x1b\\"[..]]),
+            [
+                r#"print "hello""#,
+                "execute 0xd",
+                "execute 0xa",
+                r#"csi "1;2" " " false 'q'"#,
+                r#"print "world""#,
+                r##"esc "#" '8'"##,
+                r#"osc "0;title""#,
+                r#"hook "1" "$" 'q'"#,
+                r#"put "m""#,
+                "unhook",
+            ]
+        );
+
+        // Sequences and strings split across calls come out whole
+        assert_eq!(
+            record([&b"ab\x1b["[..], b"3", b"2mc", b"\x1b]1", b"0;?\x1b", b"\\"]),
+            [
+                r#"print "ab""#,
+                r#"csi "32" "" false 'm'"#,
+                r#"print "c""#,
+                r#"osc "10;?""#,
+            ]
+        );
+    }
+
+    /// Inputs and the callbacks they should produce, covering every state of the parser and the
+    /// classes of bytes it treats differently
+    const PARSER_CORPUS: &[(&[u8], &[&str])] = &[
+        // Ground
+        (b"abc", &[r#"print "abc""#]),
+        (
+            b"a\x00\x07\x08\x1fb",
+            &[
+                r#"print "a""#,
+                "execute 0x0",
+                "execute 0x7",
+                "execute 0x8",
+                "execute 0x1f",
+                r#"print "b""#,
+            ],
+        ),
+        (b"a\x7fb", &[r#"print "ab""#]),
+        ("é⛔ל".as_bytes(), &[r#"print "é⛔ל""#]),
+        (b"a\x84b", &[r#"print "a""#, "execute 0x84", r#"print "b""#]),
+        (b"a\x9cb", &[r#"print "ab""#]),
+        (
+            b"\xe2A\x9b1m",
+            &["print \"\u{fffd}A\"", r#"csi "1" "" false 'm'"#],
+        ),
+        // Escape and EscapeIntermediate
+        (b"\x1b7", &[r#"esc "" '7'"#]),
+        (b"\x1b(B", &[r#"esc "(" 'B'"#]),
+        (b"\x1b#8", &[r##"esc "#" '8'"##]),
+        (b"\x1b(\x00B", &["execute 0x0", r#"esc "(" 'B'"#]),
+        (b"\x1b\x7f7", &[r#"esc "" '7'"#]),
+        (b"\x1b\x1b7", &[r#"esc "" '7'"#]),
+        (b"\x1b\x18a", &["execute 0x18", r#"print "a""#]),
+        (b"\x1b(\x1aa", &["execute 0x1a", r#"print "a""#]),
+        (b"\x1b !\"7a", &[r#"print "a""#]),
+        (b"\x1b\\a", &[r#"print "a""#]),
+        (b"\x1b\x84", &["execute 0x84"]),
+        // CsiEntry, CsiParam, CsiIntermediate and CsiIgnore
+        (b"\x1b[m", &[r#"csi "" "" false 'm'"#]),
+        (b"\x1b[1;2m", &[r#"csi "1;2" "" false 'm'"#]),
+        (b"\x1b[?25h", &[r#"csi "?25" "" false 'h'"#]),
+        (b"\x1b[>c", &[r#"csi ">" "" false 'c'"#]),
+        (b"\x1b[4:3m", &[r#"csi "4:3" "" false 'm'"#]),
+        (b"\x1b[ q", &[r#"csi "" " " false 'q'"#]),
+        (b"\x1b[2 q", &[r#"csi "2" " " false 'q'"#]),
+        (
+            b"\x1b[?0123456789:;!\"}",
+            &[r#"csi "?65535:;" "!\"" false '}'"#],
+        ),
+        (
+            b"\x1b[4:3;38:2::1:2:3;;m",
+            &[r#"csi "4:3;38:2::1:2:3;;" "" false 'm'"#],
+        ),
+        (
+            b"\x1b[007;99999999999999999999999H",
+            &[r#"csi "7;65535" "" false 'H'"#],
+        ),
+        (
+            b"\x1b[;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;m",
+            &[r#"csi ";;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;" "" false 'm'"#],
+        ),
+        (
+            b"\x1b[;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;m",
+            &[r#"csi ";;;;;;;;;;;;;;;;;;;;;;;;;;;;;;;" "" true 'm'"#],
+        ),
+        (b"\x1b[1:::::::::m", &[r#"csi "1::::::::" "" true 'm'"#]),
+        (
+            b"\x1b[1\x002m",
+            &["execute 0x0", r#"csi "12" "" false 'm'"#],
+        ),
+        (b"\x1b[1\x7f2m", &[r#"csi "12" "" false 'm'"#]),
+        (b"\x1b[1?m", &[r#"csi "1" "" true 'm'"#]),
+        (b"\x1b[1 1m", &[r#"csi "1" " " true 'm'"#]),
+        (b"\x1b[0$0m", &[r#"csi "0" "$" true 'm'"#]),
+        (b"\x1b[-23;H", &[r#"csi "" "-" true 'H'"#]),
+        (b"\x1b[1 !\"m", &[r#"csi "1" " !" true 'm'"#]),
+        (b"\x1b[1?\x002m", &["execute 0x0", r#"csi "1" "" true 'm'"#]),
+        (b"\x1b[1\x1b[2m", &[r#"csi "2" "" false 'm'"#]),
+        (b"\x1b[1\x18m", &["execute 0x18", r#"print "m""#]),
+        (b"\x1b[1?\x1am", &["execute 0x1a", r#"print "m""#]),
+        (b"\x1b[1\x9b2m", &[r#"csi "2" "" false 'm'"#]),
+        (b"\x1b[1\xc3m", &[r#"csi "1" "" false 'm'"#]),
+        (b"\x9b1m", &[r#"csi "1" "" false 'm'"#]),
+        // OscString
+        (b"\x1b]0;title\x07", &[r#"osc "0;title""#]),
+        (b"\x1b]0;title\x1b\\", &[r#"osc "0;title""#]),
+        (b"\x1b]0;title\x9c", &[r#"osc "0;title""#]),
+        (b"\x9d0;title\x07", &[r#"osc "0;title""#]),
+        (b"\x1b]0;t\x00i\x07", &[r#"osc "0;ti""#]),
+        ("\x1b]0;ל\x07".as_bytes(), &[r#"osc "0;ל""#]),
+        (b"\x1b]0;title\x18a", &["execute 0x18", r#"print "a""#]),
+        (
+            b"\x1b]0;a\x1b[m",
+            &[r#"osc "0;a""#, r#"csi "" "" false 'm'"#],
+        ),
+        // DcsEntry, DcsParam, DcsIntermediate, DcsPassthrough and DcsIgnore
+        (
+            b"\x1bP1$qm\x1b\\",
+            &[r#"hook "1" "$" 'q'"#, r#"put "m""#, "unhook"],
+        ),
+        (
+            b"\x90q#0\x9c",
+            &[r#"hook "" "" 'q'"#, r##"put "#0""##, "unhook"],
+        ),
+        (
+            b"\x1bPq\x00a\x7fb\x1b\\",
+            &[r#"hook "" "" 'q'"#, r#"put "\0ab""#, "unhook"],
+        ),
+        (b"\x1bP\x00\x7fq\x1b\\", &[r#"hook "" "" 'q'"#, "unhook"]),
+        (b"\x1bP1?qabc\x1b\\d", &[r#"print "d""#]),
+        (b"\x1bP1 1qabc\x9cd", &[r#"print "d""#]),
+        (b"\x1bP !\"qabc\x1b\\d", &[r#"print "d""#]),
+        (
+            b"\x1bPqab\x18c",
+            &[
+                r#"hook "" "" 'q'"#,
+                r#"put "ab""#,
+                "unhook",
+                "execute 0x18",
+                r#"print "c""#,
+            ],
+        ),
+        // SosPmApcString
+        (b"\x1b_abc\x1b\\d", &[r#"print "d""#]),
+        (b"\x1bXabc\x9cd", &[r#"print "d""#]),
+        (b"\x1b^a\x07b\x00c\x1b\\d", &[r#"print "d""#]),
+        (b"\x9fabc\x18d", &["execute 0x18", r#"print "d""#]),
+    ];
+
+    #[test]
+    fn test_parser_corpus() {
+        for (input, expected) in PARSER_CORPUS {
+            assert_eq!(&record([*input]), expected, "{input:?}");
+
+            // Splitting the input anywhere must not change the result
+            let bytes = input.chunks(1).collect::<Vec<_>>();
+            assert_eq!(&record(bytes), expected, "{input:?} split into bytes");
+        }
+    }
+
+    #[test]
+    fn test_parser_recovers_from_every_state() {
+        // One input that ends in each of the states
+        let prefixes: &[&[u8]] = &[
+            b"",
+            b"\xc3",
+            b"\x1b",
+            b"\x1b(",
+            b"\x1b[",
+            b"\x1b[1",
+            b"\x1b[1 ",
+            b"\x1b[1?",
+            b"\x1bP",
+            b"\x1bP1",
+            b"\x1bP1$",
+            b"\x1bPq",
+            b"\x1bP1?",
+            b"\x1b]0;a",
+            b"\x1b_a",
+        ];
+
+        for prefix in prefixes {
+            for b in 0..=u8::MAX {
+                let mut input = prefix.to_vec();
+                input.extend_from_slice(&[b, 0x18, b'x']);
+                let events = record([&input[..]]);
+                assert_eq!(
+                    events.last().map(String::as_str),
+                    Some(r#"print "x""#),
+                    "{input:?}"
+                );
+            }
+        }
+    }
 }
@@ -0,0 +1,609 @@
+use std::collections::VecDeque;
+use std::ops::Range;
+
+use super::{CursorState, TerminalColor};
+
+const MAX_SCROLLBACK_LINES: usize = 10000;
+const TAB_WIDTH: usize = 8;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Cell {
+    pub c: char,
+    pub color: TerminalColor,
+    pub bold: bool,
+}
+
+impl Cell {
+    pub fn blank() -> Cell {
+        Cell {
+            c: ' ',
+            color: TerminalColor::Default,
+            bold: false,
+        }
+    }
+
+    fn with_cursor_format(c: char, cursor: &CursorState) -> Cell {
+        Cell {
+            c,
+            color: cursor.color,
+            bold: cursor.bold,
+        }
+    }
+}
+
+fn blank_line(width: usize) -> Vec<Cell> {
+    vec![Cell::blank(); width]
+}
+
+fn default_tab_stops(start: usize, width: usize) -> impl Iterator<Item = bool> {
+    (start..width).map(|x| x != 0 && x % TAB_WIDTH == 0)
+}
+
+/// Visible screen contents as a grid of cells, plus the lines that have scrolled off the top
+pub struct TerminalBuffer {
+    width: usize,
+    height: usize,
+    lines: Vec<Vec<Cell>>,
+    scrollback: VecDeque<Vec<Cell>>,
+    tab_stops: Vec<bool>,
+    /// Lines between the top and bottom margins, the whole screen unless DECSTBM narrowed it
+    scroll_region: Range<usize>,
+}
+
+impl TerminalBuffer {
+    pub fn new(width: usize, height: usize) -> TerminalBuffer {
+        TerminalBuffer {
+            width,
+            height,
+            lines: vec![blank_line(width); height],
+            scrollback: VecDeque::new(),
+            tab_stops: default_tab_stops(0, width).collect(),
+            scroll_region: 0..height,
+        }
+    }
+
+    /// Resizes the screen, pushing lines into scrollback if needed to keep the cursor visible.
+    /// Tab stops that still fit are kept, new columns get the default stops, and the scroll region
+    /// goes back to the whole screen
+    pub fn resize(&mut self, width: usize, height: usize, cursor: &mut CursorState) {
+        for line in self.lines.iter_mut().chain(self.scrollback.iter_mut()) {
+            line.resize(width, Cell::blank());
+        }
+
+        let old_width = self.tab_stops.len();
+        self.tab_stops.truncate(width);
+        self.tab_stops.extend(default_tab_stops(old_width, width));
+
+        if height < self.height {
+            let to_scroll = (cursor.y + 1).saturating_sub(height);
+            for _ in 0..to_scroll {
+                self.scroll_up();
+            }
+            self.lines.truncate(height);
+            cursor.y -= to_scroll;
+        } else {
+            self.lines.resize(height, blank_line(width));
+        }
+
+        self.width = width;
+        self.height = height;
+        self.scroll_region = 0..height;
+        cursor.x = cursor.x.min(width - 1);
+    }
+
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    pub fn height(&self) -> usize {
+        self.height
+    }
+
+    /// Writes a character at the cursor, wrapping to the next line first if the previous write
+    /// filled the line
+    pub fn write_char(&mut self, c: char, cursor: &mut CursorState) {
+        if cursor.x >= self.width {
+            cursor.x = 0;
+            self.line_feed(cursor);
+        }
+
+        self.lines[cursor.y][cursor.x] = Cell::with_cursor_format(c, cursor);
+        cursor.x += 1;
+    }
+
+    pub fn scroll_region(&self) -> Range<usize> {
+        self.scroll_region.clone()
+    }
+
+    /// Sets the lines that scroll, the caller checks the region is on the screen
+    pub fn set_scroll_region(&mut self, region: Range<usize>) {
+        debug_assert!(region.start < region.end && region.end <= self.height);
+        self.scroll_region = region;
+    }
+
+    /// Moves the cursor down a line, scrolling the region instead if the cursor is on its bottom
+    /// margin. Below the region the cursor stops at the bottom of the screen
+    pub fn line_feed(&mut self, cursor: &mut CursorState) {
+        if cursor.y + 1 == self.scroll_region.end {
+            self.scroll_region_up(1);
+        } else if cursor.y + 1 < self.height {
+            cursor.y += 1;
+        }
+    }
+
+    /// Moves the cursor up a line, scrolling the region down instead if the cursor is on its top
+    /// margin
+    pub fn reverse_index(&mut self, cursor: &mut CursorState) {
+        if cursor.y == self.scroll_region.start {
+            self.scroll_region_down(1);
+        } else if cursor.y > 0 {
+            cursor.y -= 1;
+        }
+    }
+
+    /// Scrolls the region up, blank lines come in at the bottom margin. Lines only go into
+    /// scrollback when the region is the whole screen
+    pub fn scroll_region_up(&mut self, count: usize) {
+        let region = self.scroll_region.clone();
+        let count = count.min(region.len());
+        if region == (0..self.height) {
+            for _ in 0..count {
+                self.scroll_up();
+            }
+            return;
+        }
+
+        for _ in 0..count {
+            self.lines.remove(region.start);
+            self.lines.insert(region.end - 1, blank_line(self.width));
+        }
+    }
+
+    /// Scrolls the region down, blank lines come in at the top margin
+    pub fn scroll_region_down(&mut self, count: usize) {
+        let region = self.scroll_region.clone();
+        for _ in 0..count.min(region.len()) {
+            self.lines.remove(region.end - 1);
+            self.lines.insert(region.start, blank_line(self.width));
+        }
+    }
+
+    /// Inserts blank lines at y, pushing the lines below down and off the bottom margin. Does
+    /// nothing outside the scroll region
+    pub fn insert_lines(&mut self, y: usize, count: usize) {
+        let region = self.scroll_region.clone();
+        if !region.contains(&y) {
+            return;
+        }
+
+        for _ in 0..count.min(region.end - y) {
+            self.lines.remove(region.end - 1);
+            self.lines.insert(y, blank_line(self.width));
+        }
+    }
+
+    /// Deletes lines at y, pulling the lines below up and blanks in at the bottom margin. Does
+    /// nothing outside the scroll region
+    pub fn delete_lines(&mut self, y: usize, count: usize) {
+        let region = self.scroll_region.clone();
+        if !region.contains(&y) {
+            return;
+        }
+
+        for _ in 0..count.min(region.end - y) {
+            self.lines.remove(y);
+            self.lines.insert(region.end - 1, blank_line(self.width));
+        }
+    }
+
+    fn scroll_up(&mut self) {
+        let line = self.lines.remove(0);
+        self.scrollback.push_back(line);
+        if self.scrollback.len() > MAX_SCROLLBACK_LINES {
+            self.scrollback.pop_front();
+        }
+        self.lines.push(blank_line(self.width));
+    }
+
+    pub fn set_tab_stop(&mut self, x: usize) {
+        if let Some(stop) = self.tab_stops.get_mut(x) {
+            *stop = true;
+        }
+    }
+
+    pub fn clear_tab_stop(&mut self, x: usize) {
+        if let Some(stop) = self.tab_stops.get_mut(x) {
+            *stop = false;
+        }
+    }
+
+    pub fn clear_all_tab_stops(&mut self) {
+        self.tab_stops.fill(false);
+    }
+
+    /// Column of the count'th tab stop after x, or the last column if there are not enough
+    pub fn next_tab_stop(&self, x: usize, count: usize) -> usize {
+        let mut x = x.min(self.width - 1);
+        for _ in 0..count {
+            x = (x + 1..self.width)
+                .find(|x| self.tab_stops[*x])
+                .unwrap_or(self.width - 1);
+        }
+        x
+    }
+
+    /// Column of the count'th tab stop before x, or the first column if there are not enough
+    pub fn prev_tab_stop(&self, x: usize, count: usize) -> usize {
+        let mut x = x.min(self.width - 1);
+        for _ in 0..count {
+            x = (0..x).rev().find(|x| self.tab_stops[*x]).unwrap_or(0);
+        }
+        x
+    }
+
+    pub fn clear_forwards(&mut self, cursor: &CursorState) {
+        let x = cursor.x.min(self.width);
+        self.lines[cursor.y][x..].fill(Cell::blank());
+        for line in &mut self.lines[cursor.y + 1..] {
+            line.fill(Cell::blank());
+        }
+    }
+
+    pub fn clear_backwards(&mut self, cursor: &CursorState) {
+        for line in &mut self.lines[..cursor.y] {
+            line.fill(Cell::blank());
+        }
+        let x = cursor.x.min(self.width - 1);
+        self.lines[cursor.y][..=x].fill(Cell::blank());
+    }
+
+    pub fn clear_all(&mut self) {
+        for line in &mut self.lines {
+            line.fill(Cell::blank());
+        }
+    }
+
+    pub fn clear_line_forwards(&mut self, cursor: &CursorState) {
+        let x = cursor.x.min(self.width);
+        self.lines[cursor.y][x..].fill(Cell::blank());
+    }
+
+    pub fn clear_line_backwards(&mut self, cursor: &CursorState) {
+        let x = cursor.x.min(self.width - 1);
+        self.lines[cursor.y][..=x].fill(Cell::blank());
+    }
+
+    pub fn clear_line(&mut self, y: usize) {
+        self.lines[y].fill(Cell::blank());
+    }
+
+    /// Blanks count cells from the cursor without moving the rest of the line
+    pub fn erase_chars(&mut self, cursor: &CursorState, count: usize) {
+        let x = cursor.x.min(self.width - 1);
+        let end = x.saturating_add(count).min(self.width);
+        self.lines[cursor.y][x..end].fill(Cell::blank());
+    }
+
+    /// Inserts blank cells at the cursor, dropping whatever is pushed past the right edge
+    pub fn insert_chars(&mut self, cursor: &CursorState, count: usize) {
+        let x = cursor.x.min(self.width - 1);
+        let count = count.min(self.width - x);
+        let line = &mut self.lines[cursor.y];
+        line.splice(x..x, std::iter::repeat_n(Cell::blank(), count));
+        line.truncate(self.width);
+    }
+
+    /// Deletes cells at the cursor, filling in blanks from the right edge
+    pub fn delete_chars(&mut self, cursor: &CursorState, count: usize) {
+        let x = cursor.x.min(self.width - 1);
+        let count = count.min(self.width - x);
+        let line = &mut self.lines[cursor.y];
+        line.drain(x..x + count);
+        line.resize(self.width, Cell::blank());
+    }
+
+    /// Inserts blank columns at x on every line in the scroll region, dropping whatever is pushed
+    /// past the right edge
+    pub fn insert_columns(&mut self, x: usize, count: usize) {
+        if x >= self.width {
+            return;
+        }
+
+        let count = count.min(self.width - x);
+        for line in &mut self.lines[self.scroll_region.clone()] {
+            line.splice(x..x, std::iter::repeat_n(Cell::blank(), count));
+            line.truncate(self.width);
+        }
+    }
+
+    /// Deletes columns at x on every line in the scroll region, filling in blanks from the right
+    /// edge
+    pub fn delete_columns(&mut self, x: usize, count: usize) {
+        if x >= self.width {
+            return;
+        }
+
+        let count = count.min(self.width - x);
+        for line in &mut self.lines[self.scroll_region.clone()] {
+            line.drain(x..x + count);
+            line.resize(self.width, Cell::blank());
+        }
+    }
+
+    /// Fills every visible cell with c in the default rendition
+    pub fn fill(&mut self, c: char) {
+        for line in &mut self.lines {
+            line.fill(Cell { c, ..Cell::blank() });
+        }
+    }
+
+    /// Text of visible line y without its trailing blanks, None if y is past the bottom
+    pub fn line(&self, y: usize) -> Option<String> {
+        let line = self.trimmed_lines().nth(y)?;
+        Some(line.iter().map(|cell| cell.c).collect())
+    }
+
+    /// Cells of visible line y, None if y is past the bottom
+    pub fn cells(&self, y: usize) -> Option<&[Cell]> {
+        self.lines.get(y).map(Vec::as_slice)
+    }
+
+    /// Visible lines without their trailing blank cells
+    pub fn trimmed_lines(&self) -> impl Iterator<Item = &[Cell]> {
+        self.lines.iter().map(|line| {
+            let len = line
+                .iter()
+                .rposition(|cell| *cell != Cell::blank())
+                .map_or(0, |pos| pos + 1);
+            &line[..len]
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn cursor_at(x: usize, y: usize) -> CursorState {
+        CursorState {
+            x,
+            y,
+            bold: false,
+            color: TerminalColor::Default,
+        }
+    }
+
+    fn write_str(buffer: &mut TerminalBuffer, s: &str, cursor: &mut CursorState) {
+        for c in s.chars() {
+            buffer.write_char(c, cursor);
+        }
+    }
+
+    fn line_str(buffer: &TerminalBuffer, y: usize) -> String {
+        buffer.lines[y].iter().map(|cell| cell.c).collect()
+    }
+
+    #[test]
+    fn test_cursor_data_insert() {
+        let mut buffer = TerminalBuffer::new(8, 2);
+        write_str(&mut buffer, "asdf", &mut cursor_at(0, 0));
+        assert_eq!(line_str(&buffer, 0), "asdf    ");
+
+        write_str(&mut buffer, "123", &mut cursor_at(0, 0));
+        assert_eq!(line_str(&buffer, 0), "123f    ");
+
+        write_str(&mut buffer, "xyzw", &mut cursor_at(4, 0));
+        assert_eq!(line_str(&buffer, 0), "123fxyzw");
+
+        write_str(&mut buffer, "asdf", &mut cursor_at(2, 0));
+        assert_eq!(line_str(&buffer, 0), "12asdfzw");
+    }
+
+    #[test]
+    fn test_wrap_and_scroll() {
+        let mut buffer = TerminalBuffer::new(4, 2);
+        let mut cursor = cursor_at(0, 0);
+        write_str(&mut buffer, "abcdefghij", &mut cursor);
+        assert_eq!(line_str(&buffer, 0), "efgh");
+        assert_eq!(line_str(&buffer, 1), "ij  ");
+        assert_eq!(buffer.scrollback.len(), 1);
+        assert_eq!(cursor.x, 2);
+        assert_eq!(cursor.y, 1);
+
+        // Writing the last column leaves the cursor pending a wrap
+        write_str(&mut buffer, "kl", &mut cursor);
+        assert_eq!(cursor.x, 4);
+        assert_eq!(cursor.y, 1);
+        assert_eq!(buffer.scrollback.len(), 1);
+    }
+
+    #[test]
+    fn test_clear() {
+        let mut buffer = TerminalBuffer::new(4, 3);
+        let mut cursor = cursor_at(0, 0);
+        write_str(&mut buffer, "abcdefghijkl", &mut cursor);
+
+        buffer.clear_forwards(&cursor_at(2, 1));
+        assert_eq!(line_str(&buffer, 0), "abcd");
+        assert_eq!(line_str(&buffer, 1), "ef  ");
+        assert_eq!(line_str(&buffer, 2), "    ");
+
+        buffer.clear_backwards(&cursor_at(1, 1));
+        assert_eq!(line_str(&buffer, 0), "    ");
+        assert_eq!(line_str(&buffer, 1), "    ");
+
+        write_str(&mut buffer, "x", &mut cursor_at(3, 2));
+        buffer.clear_all();
+        assert!(buffer.trimmed_lines().all(|line| line.is_empty()));
+
+        buffer.fill('E');
+        assert!((0..3).all(|y| line_str(&buffer, y) == "EEEE"));
+    }
+
+    #[test]
+    fn test_tab_stops() {
+        let mut buffer = TerminalBuffer::new(20, 2);
+        assert_eq!(buffer.next_tab_stop(0, 1), 8);
+        assert_eq!(buffer.next_tab_stop(8, 1), 16);
+        assert_eq!(buffer.next_tab_stop(0, 2), 16);
+        assert_eq!(buffer.next_tab_stop(16, 1), 19);
+        assert_eq!(buffer.prev_tab_stop(17, 1), 16);
+        assert_eq!(buffer.prev_tab_stop(16, 1), 8);
+        assert_eq!(buffer.prev_tab_stop(16, 3), 0);
+
+        buffer.set_tab_stop(3);
+        buffer.clear_tab_stop(8);
+        assert_eq!(buffer.next_tab_stop(0, 1), 3);
+        assert_eq!(buffer.next_tab_stop(3, 1), 16);
+
+        buffer.clear_all_tab_stops();
+        assert_eq!(buffer.next_tab_stop(0, 1), 19);
+        assert_eq!(buffer.prev_tab_stop(19, 1), 0);
+    }
+
+    #[test]
+    fn test_resize() {
+        let mut buffer = TerminalBuffer::new(10, 3);
+        buffer.set_tab_stop(5);
+        buffer.clear_tab_stop(8);
+
+        let mut cursor = cursor_at(0, 0);
+        write_str(&mut buffer, "aaaaaaaaaabbbbbbbbbbcc", &mut cursor);
+        assert_eq!(cursor.y, 2);
+
+        buffer.resize(20, 2, &mut cursor);
+        assert_eq!(line_str(&buffer, 0), "bbbbbbbbbb          ");
+        assert_eq!(line_str(&buffer, 1), "cc                  ");
+        assert_eq!(buffer.scrollback.len(), 1);
+        assert_eq!(cursor.y, 1);
+        assert_eq!(cursor.x, 2);
+
+        // Existing stops are kept, the new columns get the default ones
+        assert_eq!(buffer.next_tab_stop(0, 1), 5);
+        assert_eq!(buffer.next_tab_stop(5, 1), 16);
+
+        buffer.resize(4, 3, &mut cursor);
+        assert_eq!(line_str(&buffer, 0), "bbbb");
+        assert_eq!(line_str(&buffer, 2), "    ");
+        assert_eq!(buffer.next_tab_stop(0, 1), 3);
+    }
+
+    #[test]
+    fn test_insert_delete_columns() {
+        let mut buffer = TerminalBuffer::new(5, 2);
+        write_str(&mut buffer, "abcdefghij", &mut cursor_at(0, 0));
+
+        buffer.insert_columns(1, 2);
+        assert_eq!(line_str(&buffer, 0), "a  bc");
+        assert_eq!(line_str(&buffer, 1), "f  gh");
+
+        buffer.delete_columns(0, 2);
+        assert_eq!(line_str(&buffer, 0), " bc  ");
+        assert_eq!(line_str(&buffer, 1), " gh  ");
+
+        buffer.delete_columns(2, 100);
+        assert_eq!(line_str(&buffer, 0), " b   ");
+        buffer.insert_columns(5, 1);
+        assert_eq!(line_str(&buffer, 0), " b   ");
+    }
+
+    #[test]
+    fn test_scroll_region() {
+        let mut buffer = TerminalBuffer::new(3, 5);
+        write_str(&mut buffer, "aaabbbcccdddeee", &mut cursor_at(0, 0));
+        buffer.set_scroll_region(1..4);
+
+        // Only the region scrolls, and nothing goes into scrollback
+        let mut cursor = cursor_at(0, 3);
+        buffer.line_feed(&mut cursor);
+        assert_eq!(cursor.y, 3);
+        assert_eq!(
+            (0..5).map(|y| line_str(&buffer, y)).collect::<Vec<_>>(),
+            ["aaa", "ccc", "ddd", "   ", "eee"]
+        );
+        assert!(buffer.scrollback.is_empty());
+
+        buffer.reverse_index(&mut cursor_at(0, 1));
+        assert_eq!(
+            (0..5).map(|y| line_str(&buffer, y)).collect::<Vec<_>>(),
+            ["aaa", "   ", "ccc", "ddd", "eee"]
+        );
+
+        // Below the region the cursor stops at the bottom of the screen
+        let mut cursor = cursor_at(0, 4);
+        buffer.line_feed(&mut cursor);
+        assert_eq!(cursor.y, 4);
+        assert_eq!(line_str(&buffer, 4), "eee");
+
+        buffer.scroll_region_up(100);
+        assert_eq!(
+            (0..5).map(|y| line_str(&buffer, y)).collect::<Vec<_>>(),
+            ["aaa", "   ", "   ", "   ", "eee"]
+        );
+    }
+
+    #[test]
+    fn test_insert_delete_lines() {
+        let mut buffer = TerminalBuffer::new(2, 4);
+        write_str(&mut buffer, "aabbccdd", &mut cursor_at(0, 0));
+        buffer.set_scroll_region(0..3);
+
+        buffer.insert_lines(1, 1);
+        assert_eq!(
+            (0..4).map(|y| line_str(&buffer, y)).collect::<Vec<_>>(),
+            ["aa", "  ", "bb", "dd"]
+        );
+
+        buffer.delete_lines(0, 2);
+        assert_eq!(
+            (0..4).map(|y| line_str(&buffer, y)).collect::<Vec<_>>(),
+            ["bb", "  ", "  ", "dd"]
+        );
+
+        // Outside the region nothing moves
+        buffer.insert_lines(3, 1);
+        buffer.delete_lines(3, 1);
+        assert_eq!(line_str(&buffer, 3), "dd");
+    }
+
+    #[test]
+    fn test_line_editing() {
+        let mut buffer = TerminalBuffer::new(6, 1);
+        write_str(&mut buffer, "abcdef", &mut cursor_at(0, 0));
+
+        buffer.insert_chars(&cursor_at(1, 0), 2);
+        assert_eq!(line_str(&buffer, 0), "a  bcd");
+        buffer.delete_chars(&cursor_at(0, 0), 1);
+        assert_eq!(line_str(&buffer, 0), "  bcd ");
+        buffer.erase_chars(&cursor_at(3, 0), 100);
+        assert_eq!(line_str(&buffer, 0), "  b   ");
+
+        write_str(&mut buffer, "xyz", &mut cursor_at(3, 0));
+        buffer.clear_line_forwards(&cursor_at(4, 0));
+        assert_eq!(line_str(&buffer, 0), "  bx  ");
+        buffer.clear_line_backwards(&cursor_at(2, 0));
+        assert_eq!(line_str(&buffer, 0), "   x  ");
+        buffer.clear_line(0);
+        assert_eq!(line_str(&buffer, 0), "      ");
+    }
+
+    #[test]
+    fn test_columns_in_scroll_region() {
+        let mut buffer = TerminalBuffer::new(3, 4);
+        let mut cursor = cursor_at(0, 0);
+        write_str(&mut buffer, "abcdefghijkl", &mut cursor);
+        buffer.set_scroll_region(1..3);
+
+        buffer.insert_columns(0, 1);
+        assert_eq!(line_str(&buffer, 0), "abc");
+        assert_eq!(line_str(&buffer, 1), " de");
+        assert_eq!(line_str(&buffer, 2), " gh");
+        assert_eq!(line_str(&buffer, 3), "jkl");
+
+        buffer.delete_columns(0, 2);
+        assert_eq!(line_str(&buffer, 0), "abc");
+        assert_eq!(line_str(&buffer, 1), "e  ");
+        assert_eq!(line_str(&buffer, 2), "h  ");
+        assert_eq!(line_str(&buffer, 3), "jkl");
+    }
+}
@@ -0,0 +1,146 @@
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Charset {
+    Ascii,
+    DecSpecialGraphics,
+    Uk,
+}
+
+impl Charset {
+    /// Final byte of an SCS sequence, e.g. the 0 in ESC ( 0
+    pub fn from_designator(b: u8) -> Option<Charset> {
+        let ret = match b {
+            b'B' => Charset::Ascii,
+            b'0' => Charset::DecSpecialGraphics,
+            b'A' => Charset::Uk,
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    fn translate(&self, c: char) -> char {
+        match self {
+            Charset::Ascii => c,
+            Charset::Uk => {
+                if c == '#' {
+                    '£'
+                } else {
+                    c
+                }
+            }
+            Charset::DecSpecialGraphics => translate_dec_special_graphics(c),
+        }
+    }
+}
+
+fn translate_dec_special_graphics(c: char) -> char {
+    match c {
+        '_' => ' ',
+        '`' => '◆',
+        'a' => '▒',
+        'b' => '␉',
+        'c' => '␌',
+        'd' => '␍',
+        'e' => '␊',
+        'f' => '°',
+        'g' => '±',
+        'h' => '␤',
+        'i' => '␋',
+        'j' => '┘',
+        'k' => '┐',
+        'l' => '┌',
+        'm' => '└',
+        'n' => '┼',
+        'o' => '⎺',
+        'p' => '⎻',
+        'q' => '─',
+        'r' => '⎼',
+        's' => '⎽',
+        't' => '├',
+        'u' => '┤',
+        'v' => '┴',
+        'w' => '┬',
+        'x' => '│',
+        'y' => '≤',
+        'z' => '≥',
+        '{' => 'π',
+        '|' => '≠',
+        '}' => '£',
+        '~' => '·',
+        _ => c,
+    }
+}
+
+/// Tracks the G0-G3 designations and which of them is currently invoked
+#[derive(Clone, Debug)]
+pub struct CharsetState {
+    slots: [Charset; 4],
+    active: usize,
+    single_shift: Option<usize>,
+}
+
+impl CharsetState {
+    pub fn new() -> CharsetState {
+        CharsetState {
+            slots: [Charset::Ascii; 4],
+            active: 0,
+            single_shift: None,
+        }
+    }
+
+    pub fn designate(&mut self, slot: usize, charset: Charset) {
+        self.slots[slot] = charset;
+    }
+
+    pub fn locking_shift(&mut self, slot: usize) {
+        self.active = slot;
+    }
+
+    pub fn single_shift(&mut self, slot: usize) {
+        self.single_shift = Some(slot);
+    }
+
+    /// Maps a printable character through the active charset, using up any pending single shift
+    pub fn translate(&mut self, c: char) -> char {
+        let slot = self.single_shift.take().unwrap_or(self.active);
+        if !(' '..='~').contains(&c) {
+            return c;
+        }
+        self.slots[slot].translate(c)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn translate_str(state: &mut CharsetState, s: &str) -> String {
+        s.chars().map(|c| state.translate(c)).collect()
+    }
+
+    #[test]
+    fn test_dec_special_graphics() {
+        let mut state = CharsetState::new();
+        assert_eq!(translate_str(&mut state, "lqqk"), "lqqk");
+
+        state.designate(0, Charset::DecSpecialGraphics);
+        assert_eq!(translate_str(&mut state, "lqqk"), "┌──┐");
+        assert_eq!(translate_str(&mut state, "x  x"), "│  │");
+        assert_eq!(translate_str(&mut state, "mqqj"), "└──┘");
+    }
+
+    #[test]
+    fn test_shifts() {
+        let mut state = CharsetState::new();
+        state.designate(1, Charset::DecSpecialGraphics);
+        state.designate(2, Charset::Uk);
+
+        state.locking_shift(1);
+        assert_eq!(translate_str(&mut state, "qq"), "──");
+        state.locking_shift(0);
+        assert_eq!(translate_str(&mut state, "qq"), "qq");
+
+        state.single_shift(2);
+        assert_eq!(translate_str(&mut state, "##"), "£#");
+    }
+}
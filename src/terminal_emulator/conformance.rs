@@ -0,0 +1,209 @@
+//! vttest style conformance suite. Every file in conformance/ is a recorded escape sequence
+//! input followed by the screen it should produce:
+//!
+//! ```text
+//! # Comments explain what the case checks
+//! size 10x4
+//! > \e[2;3Hab
+//! screen
+//! |          |
+//! |  ab      |
+//! |          |
+//! |          |
+//! cursor 2,5
+//! responses \e[0n
+//! attributes
+//! |..........|
+//! ...
+//! ```
+//!
+//! Input lines start with `> ` and are joined without separators. They understand `\e`, `\r`,
+//! `\n`, `\t`, `\xNN` and `\\`. The cursor is 1 based row then column, a column one past the
+//! width means a wrap is pending. Replies to the application are only listed if there are any,
+//! written the same way as the input. The attributes section is optional, `.` is the default
+//! rendition, `*` default bold, `krgybmcw` the eight colors and upper case the bold variants.
+//!
+//! Run with UPDATE_CONFORMANCE=1 to rewrite the expected results from the current output, then
+//! review the diff.
+
+use std::path::Path;
+
+use super::{fuzz, Cell, EmulatorCore, TerminalColor};
+
+struct Case {
+    /// Everything up to the screen section, kept as written
+    header: String,
+    width: usize,
+    height: usize,
+    input: Vec<u8>,
+    attributes: bool,
+}
+
+fn parse_input(line: &str) -> Vec<u8> {
+    let mut ret = Vec::new();
+    let mut bytes = line.bytes();
+    while let Some(b) = bytes.next() {
+        if b != b'\\' {
+            ret.push(b);
+            continue;
+        }
+
+        let escaped = match bytes.next() {
+            Some(b'e') => 0x1b,
+            Some(b'r') => b'\r',
+            Some(b'n') => b'\n',
+            Some(b't') => b'\t',
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                let hex = [bytes.next().unwrap(), bytes.next().unwrap()];
+                let hex = std::str::from_utf8(&hex).unwrap();
+                u8::from_str_radix(hex, 16).unwrap_or_else(|_| panic!("bad escape \\x{hex}"))
+            }
+            other => panic!("bad escape {other:?} in {line:?}"),
+        };
+        ret.push(escaped);
+    }
+    ret
+}
+
+fn escape(bytes: &[u8]) -> String {
+    let mut ret = String::new();
+    for b in bytes {
+        match b {
+            0x1b => ret.push_str("\\e"),
+            b'\\' => ret.push_str("\\\\"),
+            b' '..=b'~' => ret.push(char::from(*b)),
+            _ => ret.push_str(&format!("\\x{b:02x}")),
+        }
+    }
+    ret
+}
+
+fn parse_case(text: &str) -> Case {
+    let mut header = String::new();
+    let mut size = None;
+    let mut input = Vec::new();
+    for line in text.lines() {
+        if line == "screen" {
+            break;
+        }
+        header.push_str(line);
+        header.push('\n');
+
+        if let Some(line) = line.strip_prefix("> ") {
+            input.extend(parse_input(line));
+        } else if let Some(line) = line.strip_prefix("size ") {
+            let (width, height) = line.split_once('x').expect("size should be WxH");
+            size = Some((width.parse().unwrap(), height.parse().unwrap()));
+        } else {
+            assert!(line.is_empty() || line.starts_with('#'), "{line:?}");
+        }
+    }
+
+    let (width, height) = size.expect("case should have a size");
+    Case {
+        header,
+        width,
+        height,
+        input,
+        attributes: text.lines().any(|line| line == "attributes"),
+    }
+}
+
+fn attribute_char(cell: &Cell) -> char {
+    let c = match cell.color {
+        TerminalColor::Default => return if cell.bold { '*' } else { '.' },
+        TerminalColor::Black => 'k',
+        TerminalColor::Red => 'r',
+        TerminalColor::Green => 'g',
+        TerminalColor::Yellow => 'y',
+        TerminalColor::Blue => 'b',
+        TerminalColor::Magenta => 'm',
+        TerminalColor::Cyan => 'c',
+        TerminalColor::White => 'w',
+    };
+    if cell.bold {
+        c.to_ascii_uppercase()
+    } else {
+        c
+    }
+}
+
+/// The case file as it would look if the emulator's output was the expected result
+fn render(case: &Case, emulator: &mut EmulatorCore) -> String {
+    let responses = emulator.take_responses();
+    let screen = emulator.screen();
+    let rows = || (0..screen.height()).map_while(|y| screen.cells(y));
+
+    let mut ret = case.header.clone();
+    ret.push_str("screen\n");
+    for row in rows() {
+        let text = row.iter().map(|cell| cell.c).collect::<String>();
+        ret.push_str(&format!("|{text}|\n"));
+    }
+
+    let cursor = emulator.cursor_pos();
+    ret.push_str(&format!("cursor {},{}\n", cursor.y + 1, cursor.x + 1));
+
+    if !responses.is_empty() {
+        ret.push_str(&format!("responses {}\n", escape(&responses)));
+    }
+
+    if case.attributes {
+        ret.push_str("attributes\n");
+        for row in rows() {
+            let attributes = row.iter().map(attribute_char).collect::<String>();
+            ret.push_str(&format!("|{attributes}|\n"));
+        }
+    }
+    ret
+}
+
+fn run_case(path: &Path) {
+    let expected = std::fs::read_to_string(path).unwrap();
+    let case = parse_case(&expected);
+
+    let mut emulator = EmulatorCore::new(case.width, case.height);
+    emulator.feed(&case.input);
+    let actual = render(&case, &mut emulator);
+
+    if std::env::var_os("UPDATE_CONFORMANCE").is_some() {
+        std::fs::write(path, &actual).unwrap();
+        return;
+    }
+    assert_eq!(actual, expected, "{}", path.display());
+
+    // Where the reads split the input must not matter
+    let mut chunked = EmulatorCore::new(case.width, case.height);
+    for chunk in fuzz::chunks(&case.input) {
+        chunked.feed(chunk);
+    }
+    assert_eq!(
+        render(&case, &mut chunked),
+        actual,
+        "{} chunked",
+        path.display()
+    );
+}
+
+#[test]
+fn test_conformance() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/conformance");
+    let mut paths = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    paths.sort();
+
+    for path in &paths {
+        run_case(path);
+    }
+    assert!(!paths.is_empty(), "no cases in {dir}");
+}
+
+#[test]
+fn test_parse_input() {
+    let input = parse_input(r"a\e[1mb\r\n\x0e\\");
+    assert_eq!(input, b"a\x1b[1mb\r\n\x0e\\");
+    assert_eq!(parse_input(&escape(&input)), input);
+}
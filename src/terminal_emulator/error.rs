@@ -0,0 +1,39 @@
+use std::fmt;
+
+use nix::errno::Errno;
+
+/// Failures talking to the pty or the child. Output from the child is never an error, anything
+/// that can't be understood is logged and skipped
+#[derive(Debug)]
+pub enum TerminalError {
+    /// Opening the pty or forking the child failed
+    Spawn(Errno),
+    /// The pty could not be set up for non blocking reads
+    PtySetup(Errno),
+    /// Sending input to the child failed
+    Write(Errno),
+    /// The thread that reads the pty could not be started
+    ReaderThread(std::io::Error),
+}
+
+impl fmt::Display for TerminalError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TerminalError::Spawn(e) => write!(f, "failed to start the child on a pty: {e}"),
+            TerminalError::PtySetup(e) => write!(f, "failed to set up the pty: {e}"),
+            TerminalError::Write(e) => write!(f, "failed to write to the pty: {e}"),
+            TerminalError::ReaderThread(e) => write!(f, "failed to start the pty reader: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TerminalError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TerminalError::Spawn(e) | TerminalError::PtySetup(e) | TerminalError::Write(e) => {
+                Some(e)
+            }
+            TerminalError::ReaderThread(e) => Some(e),
+        }
+    }
+}
@@ -0,0 +1,122 @@
+//! Checks run by the fuzz targets in fuzz/, kept here so they can reach parser and emulator
+//! internals and so the tests can run them over the seed corpus
+
+pub use super::ansi::{AnsiParser, TerminalOutput};
+use super::EmulatorCore;
+
+/// Splits input into chunks of pseudo random length between 1 and 64. The lengths are seeded from
+/// the input itself, so a crashing input reproduces with the same splits
+pub fn chunks(input: &[u8]) -> Vec<&[u8]> {
+    // FNV-1a
+    let mut state = input.iter().fold(0x811c9dc5u32, |hash, b| {
+        (hash ^ u32::from(*b)).wrapping_mul(0x01000193)
+    });
+
+    let mut chunks = Vec::new();
+    let mut remaining = input;
+    while !remaining.is_empty() {
+        state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+        let len = ((state >> 8) as usize % 64 + 1).min(remaining.len());
+        let (chunk, rest) = remaining.split_at(len);
+        chunks.push(chunk);
+        remaining = rest;
+    }
+    chunks
+}
+
+/// Data runs end at every push, so chunked output only matches once they are joined back up
+fn join_data(output: Vec<TerminalOutput>) -> Vec<TerminalOutput> {
+    let mut joined = Vec::with_capacity(output.len());
+    for segment in output {
+        if let (Some(TerminalOutput::Data(data)), TerminalOutput::Data(more)) =
+            (joined.last_mut(), &segment)
+        {
+            data.extend_from_slice(more);
+            continue;
+        }
+        joined.push(segment);
+    }
+    joined
+}
+
+/// Panics if parsing input in chunks gives a different result than parsing it in one go
+pub fn check_chunked_parse(input: &[u8]) {
+    let whole = AnsiParser::new().push(input);
+
+    let mut parser = AnsiParser::new();
+    let mut chunked = Vec::new();
+    for chunk in chunks(input) {
+        chunked.extend(parser.push(chunk));
+    }
+
+    assert_eq!(join_data(whole), join_data(chunked), "{input:?}");
+}
+
+/// Panics if the cursor is off the screen or the formatting doesn't cover the text exactly once
+pub fn check_invariants(emulator: &EmulatorCore) {
+    let cursor = &emulator.cursor_pos;
+    // One past the last column is where the next character wraps
+    assert!(
+        cursor.x <= emulator.buffer.width(),
+        "cursor x {} past width {}",
+        cursor.x,
+        emulator.buffer.width()
+    );
+    assert!(
+        cursor.y < emulator.buffer.height(),
+        "cursor y {} past height {}",
+        cursor.y,
+        emulator.buffer.height()
+    );
+
+    let tags = emulator.format_data();
+    let mut end = 0;
+    for tag in &tags {
+        assert_eq!(tag.start, end, "gap or overlap in {tags:?}");
+        assert!(tag.start < tag.end, "empty tag in {tags:?}");
+        end = tag.end;
+    }
+    assert_eq!(end, usize::MAX, "{tags:?} stop short");
+}
+
+/// Feeds input to the emulator in chunks, checking the invariants after each
+pub fn check_emulator(emulator: &mut EmulatorCore, input: &[u8]) {
+    for chunk in chunks(input) {
+        emulator.feed(chunk);
+        // Nobody is listening for replies
+        emulator.take_responses();
+        check_invariants(emulator);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_chunks() {
+        let input = (0..=u8::MAX).cycle().take(1000).collect::<Vec<_>>();
+        let chunks = chunks(&input);
+        assert!(chunks.len() > 1);
+        assert!(chunks.iter().all(|chunk| (1..=64).contains(&chunk.len())));
+        assert_eq!(chunks.concat(), input);
+        assert!(super::chunks(b"").is_empty());
+    }
+
+    #[test]
+    fn test_seed_corpus() {
+        let seeds = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/seeds");
+        let mut count = 0;
+        for entry in std::fs::read_dir(seeds).unwrap() {
+            let input = std::fs::read(entry.unwrap().path()).unwrap();
+            check_chunked_parse(&input);
+
+            let mut emulator = EmulatorCore::new(80, 24);
+            check_emulator(&mut emulator, &input);
+            emulator.set_win_size(7, 3);
+            check_emulator(&mut emulator, &input);
+            count += 1;
+        }
+        assert!(count > 0, "no seeds in {seeds}");
+    }
+}
@@ -1,191 +1,118 @@
-use nix::{errno::Errno, unistd::ForkResult};
 use std::{
-    ffi::CStr,
+    fmt,
     ops::Range,
-    os::fd::{AsRawFd, OwnedFd},
+    time::{Duration, Instant},
 };
 
-use ansi::{AnsiParser, SelectGraphicRendition, TerminalOutput};
-
-mod ansi;
-
-/// Spawn a shell in a child process and return the file descriptor used for I/O
-fn spawn_shell() -> OwnedFd {
-    unsafe {
-        let res = nix::pty::forkpty(None, None).unwrap();
-        match res.fork_result {
-            ForkResult::Parent { .. } => (),
-            ForkResult::Child => {
-                let shell_name = CStr::from_bytes_with_nul(b"bash\0")
-                    .expect("Should always have null terminator");
-                let args: &[&[u8]] = &[b"bash\0", b"--noprofile\0", b"--norc\0"];
-
-                let args: Vec<&'static CStr> = args
-                    .iter()
-                    .map(|v| {
-                        CStr::from_bytes_with_nul(v).expect("Should always have null terminator")
-                    })
-                    .collect::<Vec<_>>();
-
-                // Temporary workaround to avoid rendering issues
-                std::env::remove_var("PROMPT_COMMAND");
-                std::env::set_var("PS1", "$ ");
-                nix::unistd::execvp(shell_name, &args).unwrap();
-                // Should never run
-                std::process::exit(1);
-            }
-        }
-        res.master
-    }
-}
-
-fn update_cursor(incoming: &[u8], cursor: &mut CursorState) {
-    for c in incoming {
-        match c {
-            b'\n' => {
-                cursor.x = 0;
-                cursor.y += 1;
-            }
-            _ => {
-                cursor.x += 1;
-            }
-        }
-    }
-}
-
-fn set_nonblock(fd: &OwnedFd) {
-    let flags = nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFL).unwrap();
-    let mut flags =
-        nix::fcntl::OFlag::from_bits(flags & nix::fcntl::OFlag::O_ACCMODE.bits()).unwrap();
-    flags.set(nix::fcntl::OFlag::O_NONBLOCK, true);
+use ansi::{
+    dispatch_csi, dispatch_esc, dispatch_osc, AnsiParser, Csi, DecMode, Perform,
+    SelectGraphicRendition, TerminalOutput,
+};
+use charset::CharsetState;
+use mouse::encode_mouse_event;
+use palette::format_color_spec;
 
-    nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_SETFL(flags)).unwrap();
-}
+pub use ansi::{CursorShape, CursorStyle};
+pub use buffer::{Cell, TerminalBuffer};
+pub use error::TerminalError;
 
-fn cursor_to_buffer_position(cursor_pos: &CursorState, buf: &[u8]) -> usize {
-    let line_start = buf
-        .split(|b| *b == b'\n')
-        .take(cursor_pos.y)
-        .fold(0, |acc, item| acc + item.len() + 1);
-    line_start + cursor_pos.x
-}
+pub use mouse::{
+    MouseButton, MouseEncoding, MouseEvent, MouseEventKind, MouseModifiers, MouseTracking,
+};
+pub use palette::{Palette, Rgb};
+pub use pty::Pty;
+pub use reader::spawn_reader_thread;
+pub use session::SessionConfig;
+pub use unhandled::log_summary as log_unhandled_summary;
 
-/// Inserts data at position in buf, extending if necessary
-fn insert_data_at_position(data: &[u8], pos: usize, buf: &mut Vec<u8>) {
-    assert!(
-        pos <= buf.len(),
-        "assume pos is never more than 1 past the end of the buffer"
-    );
+pub mod ansi;
+mod buffer;
+mod charset;
+#[cfg(test)]
+mod conformance;
+mod error;
+#[cfg(any(test, fuzzing))]
+pub mod fuzz;
+mod mouse;
+mod palette;
+mod pty;
+mod reader;
+mod session;
+mod terminfo;
+mod unhandled;
 
-    if pos >= buf.len() {
-        assert_eq!(pos, buf.len());
-        buf.extend_from_slice(data);
-        return;
-    }
+/// Size used until the GUI knows how much space it has
+const DEFAULT_WIDTH: usize = 80;
+const DEFAULT_HEIGHT: usize = 24;
 
-    let amount_that_fits = buf.len() - pos;
-    let (data_to_copy, data_to_push): (&[u8], &[u8]) = if amount_that_fits > data.len() {
-        (&data, &[])
-    } else {
-        data.split_at(amount_that_fits)
-    };
+/// Reported by DA1 as a VT220 with ANSI color support
+const DEVICE_ATTRIBUTES_RESPONSE: &[u8] = b"\x1b[?62;22c";
 
-    buf[pos..pos + data_to_copy.len()].copy_from_slice(data_to_copy);
-    buf.extend_from_slice(data_to_push);
-}
+const DEFAULT_CURSOR_STYLE: CursorStyle = CursorStyle {
+    shape: CursorShape::Block,
+    blinking: false,
+};
 
-fn delete_items_from_vec<T>(mut to_delete: Vec<usize>, vec: &mut Vec<T>) {
-    to_delete.sort();
-    for idx in to_delete.iter().rev() {
-        vec.remove(*idx);
-    }
+/// Encodes the crate version as a single number for the secondary device attributes report, e.g.
+/// 1.2.3 becomes 10203
+fn terminal_version_number() -> usize {
+    let parse = |v: &str| v.parse::<usize>().unwrap_or(0);
+    parse(env!("CARGO_PKG_VERSION_MAJOR")) * 10000
+        + parse(env!("CARGO_PKG_VERSION_MINOR")) * 100
+        + parse(env!("CARGO_PKG_VERSION_PATCH"))
 }
 
-struct ColorRangeAdjustment {
-    should_delete: bool,
-    to_insert: Option<FormatTag>,
-}
+/// Size of each read from the pty
+const READ_BUF_SIZE: usize = 64 * 1024;
 
-fn range_fully_conatins(a: &Range<usize>, b: &Range<usize>) -> bool {
-    a.start <= b.start && a.end >= b.end
-}
+/// Most output handled by a single call to read, so that a flood of output can't keep the GUI
+/// from getting at the emulator
+const READ_BATCH_LIMIT: usize = 1024 * 1024;
 
-fn range_starts_overlapping(a: &Range<usize>, b: &Range<usize>) -> bool {
-    a.start > b.start && a.end > b.end
-}
+/// Applications that open a synchronized update and never close it should not freeze the screen
+const SYNCHRONIZED_UPDATE_TIMEOUT: Duration = Duration::from_millis(150);
 
-fn range_ends_overlapping(a: &Range<usize>, b: &Range<usize>) -> bool {
-    range_starts_overlapping(b, a)
+/// Strips control characters from pasted text so that it cannot inject escape sequences, e.g. a
+/// premature end of a bracketed paste. Line endings are sent as carriage returns like a typed
+/// enter key
+fn sanitize_paste(text: &str) -> Vec<u8> {
+    let text = text.replace("\r\n", "\r").replace('\n', "\r");
+    text.chars()
+        .filter(|c| !c.is_control() || *c == '\r' || *c == '\t')
+        .collect::<String>()
+        .into_bytes()
 }
 
-fn adjust_existing_color_range(
-    existing_elem: &mut FormatTag,
-    range: &Range<usize>,
-) -> ColorRangeAdjustment {
-    let mut ret = ColorRangeAdjustment {
-        should_delete: false,
-        to_insert: None,
-    };
-
-    let existing_range = existing_elem.start..existing_elem.end;
-    if range_fully_conatins(range, &existing_range) {
-        ret.should_delete = true;
-    } else if range_fully_conatins(&existing_range, range) {
-        if existing_elem.start == range.start {
-            ret.should_delete = true;
-        }
-
-        if range.end != existing_elem.end {
-            ret.to_insert = Some(FormatTag {
-                start: range.end,
-                end: existing_elem.end,
-                color: existing_elem.color,
-                bold: existing_elem.bold
-            });
-        }
-
-        existing_elem.end = range.start;
-    } else if range_starts_overlapping(range, &existing_range) {
-        existing_elem.end = range.start;
-        if existing_elem.start == existing_elem.end {
-            ret.should_delete = true;
-        }
-    } else if range_ends_overlapping(range, &existing_range) {
-        existing_elem.start = range.end;
-        if existing_elem.start == existing_elem.end {
-            ret.should_delete = true;
-        }
-    } else {
-        panic!(
-            "Unhandled case {}-{}, {}-{}",
-            existing_elem.start, existing_elem.end, range.start, range.end
-        );
-    }
+/// Decodes incoming bytes as utf8, holding back a sequence that is split across reads until the
+/// rest of it arrives
+fn decode_utf8(pending: &mut Vec<u8>, data: &[u8]) -> Vec<char> {
+    let mut buf = std::mem::take(pending);
+    buf.extend_from_slice(data);
 
-    ret
-}
-
-fn adjust_existing_color_ranges(existing: &mut Vec<FormatTag>, range: &Range<usize>) {
-    let mut effected_infos = existing
-        .iter_mut()
-        .enumerate()
-        .filter(|(_i, item)| ranges_overlap(item.start..item.end, range.clone()))
-        .collect::<Vec<_>>();
+    let mut ret = Vec::new();
+    let mut input = &buf[..];
+    loop {
+        match std::str::from_utf8(input) {
+            Ok(valid) => {
+                ret.extend(valid.chars());
+                break;
+            }
+            Err(e) => {
+                let (valid, rest) = input.split_at(e.valid_up_to());
+                let valid = std::str::from_utf8(valid).expect("checked above");
+                ret.extend(valid.chars());
 
-    let mut to_delete = Vec::new();
-    let mut to_push = Vec::new();
-    for info in &mut effected_infos {
-        let adjustment = adjust_existing_color_range(info.1, range);
-        if adjustment.should_delete {
-            to_delete.push(info.0);
-        }
-        if let Some(item) = adjustment.to_insert {
-            to_push.push(item);
+                let Some(invalid_len) = e.error_len() else {
+                    pending.extend_from_slice(rest);
+                    break;
+                };
+                ret.push(char::REPLACEMENT_CHARACTER);
+                input = &rest[invalid_len..];
+            }
         }
     }
 
-    delete_items_from_vec(to_delete, existing);
-    existing.extend(to_push);
+    ret
 }
 
 #[derive(Clone)]
@@ -227,18 +154,6 @@ impl TerminalColor {
     }
 }
 
-fn ranges_overlap(a: Range<usize>, b: Range<usize>) -> bool {
-    if a.end <= b.start {
-        return false;
-    }
-
-    if a.start >= b.end {
-        return false;
-    }
-
-    true
-}
-
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct FormatTag {
     pub start: usize,
@@ -247,255 +162,1346 @@ pub struct FormatTag {
     pub bold: bool,
 }
 
-struct FormatTracker {
-    color_info: Vec<FormatTag>,
+/// How the child process ended
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ChildExitStatus {
+    Exited(i32),
+    Signaled(i32),
 }
 
-impl FormatTracker {
-    fn new() -> FormatTracker {
-        FormatTracker {
-            color_info: vec![FormatTag {
-                start: 0,
-                end: usize::MAX,
-                color: TerminalColor::Default,
-                bold: false,
-            }],
+impl fmt::Display for ChildExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChildExitStatus::Exited(code) => write!(f, "exited with code {code}"),
+            ChildExitStatus::Signaled(signal) => write!(f, "killed by signal {signal}"),
         }
     }
+}
 
-    fn push_range(&mut self, cursor: &CursorState, range: Range<usize>) {
-        adjust_existing_color_ranges(&mut self.color_info, &range);
+pub enum TerminalEvent {
+    /// The child was reaped, only reported once
+    ChildExited { status: ChildExitStatus },
+}
 
-        self.color_info.push(FormatTag {
-            start: range.start,
-            end: range.end,
-            color: cursor.color,
-            bold: cursor.bold,
-        });
+/// The last complete screen, shown instead of the live state while a synchronized update is open
+#[derive(Clone)]
+pub struct TerminalSnapshot {
+    pub data: Vec<u8>,
+    pub format_data: Vec<FormatTag>,
+    pub cursor_pos: CursorState,
+}
 
-        // FIXME: Insertion sort
-        // FIXME: Merge adjacent
-        self.color_info.sort_by(|a, b| a.start.cmp(&b.start));
-    }
+struct SynchronizedUpdate {
+    started: Instant,
+    snapshot: TerminalSnapshot,
+}
 
-    fn tags(&self) -> Vec<FormatTag> {
-        self.color_info.clone()
-    }
+/// What DECSC saves and DECRC puts back
+struct SavedCursor {
+    cursor_pos: CursorState,
+    origin_mode: bool,
+    charsets: CharsetState,
 }
 
-pub struct TerminalEmulator {
+/// Everything that happens between the bytes the application writes and the screen, without a
+/// pty or window, so it can be fed output directly
+pub struct EmulatorCore {
     output_buf: AnsiParser,
-    buf: Vec<u8>,
-    format_tracker: FormatTracker,
+    buffer: TerminalBuffer,
+    charsets: CharsetState,
+    utf8_pending: Vec<u8>,
+    /// Last printed character, for REP
+    last_char: Option<char>,
     cursor_pos: CursorState,
-    fd: OwnedFd,
+    palette: Palette,
+    responses: Vec<u8>,
+    mouse_tracking: MouseTracking,
+    mouse_encoding: MouseEncoding,
+    last_mouse_cell: Option<(usize, usize)>,
+    bracketed_paste: bool,
+    focus_reporting: bool,
+    synchronized_update: Option<SynchronizedUpdate>,
+    cursor_style: CursorStyle,
+    cursor_visible: bool,
+    /// DECOM, cursor addressing relative to the scroll region
+    origin_mode: bool,
+    /// DECAWM, off means writing past the last column overwrites it instead of wrapping
+    autowrap: bool,
+    saved_cursor: Option<SavedCursor>,
 }
 
-impl TerminalEmulator {
-    pub fn new() -> TerminalEmulator {
-        let fd = spawn_shell();
-        set_nonblock(&fd);
+impl EmulatorCore {
+    pub fn new(width: usize, height: usize) -> EmulatorCore {
+        let width = width.clamp(1, u16::MAX as usize);
+        let height = height.clamp(1, u16::MAX as usize);
 
-        TerminalEmulator {
+        EmulatorCore {
             output_buf: AnsiParser::new(),
-            buf: Vec::new(),
-            format_tracker: FormatTracker::new(),
+            buffer: TerminalBuffer::new(width, height),
+            charsets: CharsetState::new(),
+            utf8_pending: Vec::new(),
+            last_char: None,
             cursor_pos: CursorState {
                 x: 0,
                 y: 0,
                 bold: false,
                 color: TerminalColor::Default,
             },
-            fd,
+            palette: Palette::new(),
+            responses: Vec::new(),
+            mouse_tracking: MouseTracking::None,
+            mouse_encoding: MouseEncoding::Default,
+            last_mouse_cell: None,
+            bracketed_paste: false,
+            focus_reporting: false,
+            synchronized_update: None,
+            cursor_style: DEFAULT_CURSOR_STYLE,
+            cursor_visible: true,
+            origin_mode: false,
+            autowrap: true,
+            saved_cursor: None,
         }
     }
 
-    pub fn write(&mut self, mut to_write: &[u8]) {
-        while !to_write.is_empty() {
-            let written = nix::unistd::write(self.fd.as_raw_fd(), to_write).unwrap();
-            to_write = &to_write[written..];
+    /// Queues a reply to the application, sent once the current batch of output is processed so
+    /// that replies stay in the same order as the requests
+    fn respond(&mut self, response: &[u8]) {
+        self.responses.extend_from_slice(response);
+    }
+
+    /// Replies queued for the application since the last call, e.g. to a cursor position request
+    pub fn take_responses(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.responses)
+    }
+
+    fn set_mouse_tracking(&mut self, tracking: MouseTracking, enabled: bool) {
+        if enabled {
+            self.mouse_tracking = tracking;
+        } else if self.mouse_tracking == tracking {
+            self.mouse_tracking = MouseTracking::None;
         }
+        self.last_mouse_cell = None;
     }
 
-    pub fn read(&mut self) {
-        let mut buf = vec![0u8; 4096];
-        let mut ret = Ok(0);
-        while ret.is_ok() {
-            ret = nix::unistd::read(self.fd.as_raw_fd(), &mut buf);
-            let Ok(read_size) = ret else {
-                break;
-            };
+    fn set_mouse_encoding(&mut self, encoding: MouseEncoding, enabled: bool) {
+        if enabled {
+            self.mouse_encoding = encoding;
+        } else if self.mouse_encoding == encoding {
+            self.mouse_encoding = MouseEncoding::Default;
+        }
+    }
 
-            let incoming = &buf[0..read_size];
-            let parsed = self.output_buf.push(incoming);
-            for segment in parsed {
-                match segment {
-                    TerminalOutput::Data(data) => {
-                        let output_start = cursor_to_buffer_position(&self.cursor_pos, &self.buf);
-                        insert_data_at_position(&data, output_start, &mut self.buf);
-                        self.format_tracker.push_range(
-                            &self.cursor_pos,
-                            output_start..output_start + data.len(),
-                        );
-                        update_cursor(&data, &mut self.cursor_pos);
-                    }
-                    TerminalOutput::SetCursorPos { x, y } => {
-                        if let Some(x) = x {
-                            self.cursor_pos.x = x - 1;
-                        }
-                        if let Some(y) = y {
-                            self.cursor_pos.y = y - 1;
-                        }
-                    }
-                    TerminalOutput::ClearForwards => {
-                        let buf_pos = cursor_to_buffer_position(&self.cursor_pos, &self.buf);
-                        self.format_tracker
-                            .push_range(&self.cursor_pos, buf_pos..usize::MAX);
-                        self.buf = self.buf[..buf_pos].to_vec();
-                    }
-                    TerminalOutput::ClearBackwards => {
-                        // FIXME: Write a test to check expected behavior here, might expect
-                        // existing content to stay in the same position
-                        // FIXME: Track color
-                        let buf_pos = cursor_to_buffer_position(&self.cursor_pos, &self.buf);
-                        self.buf = self.buf[buf_pos..].to_vec();
-                    }
-                    TerminalOutput::ClearAll => {
-                        self.format_tracker
-                            .push_range(&self.cursor_pos, 0..usize::MAX);
-                        self.buf.clear();
-                    }
-                    TerminalOutput::Sgr(sgr) => {
-                        //TODO: should be match?
-                        if let Some(color) = TerminalColor::from_sgr(sgr) {
-                            self.cursor_pos.color = color;
-                        } else if sgr == SelectGraphicRendition::Reset {
-                            self.cursor_pos.color = TerminalColor::Default;
-                            self.cursor_pos.bold = false; 
-                        } else if sgr == SelectGraphicRendition::Bold {
-                            self.cursor_pos.bold = true;
-                        } else {
-                            println!("Unhandled sgr: {:?}", sgr);
-                        }
-                    }
-                    TerminalOutput::Invalid => {}
-                }
+    fn set_synchronized_update(&mut self, enabled: bool) {
+        if !enabled {
+            self.synchronized_update = None;
+            return;
+        }
+
+        // Nested begins keep the original snapshot and timeout
+        if self.synchronized_update.is_some() {
+            return;
+        }
+
+        self.synchronized_update = Some(SynchronizedUpdate {
+            started: Instant::now(),
+            snapshot: self.live_snapshot(),
+        });
+    }
+
+    fn mode_state(&self, mode: DecMode) -> Option<bool> {
+        let ret = match mode {
+            DecMode::Origin => self.origin_mode,
+            DecMode::AutoWrap => self.autowrap,
+            DecMode::ShowCursor => self.cursor_visible,
+            DecMode::X10Mouse => self.mouse_tracking == MouseTracking::X10,
+            DecMode::NormalMouse => self.mouse_tracking == MouseTracking::Normal,
+            DecMode::ButtonEventMouse => self.mouse_tracking == MouseTracking::ButtonEvent,
+            DecMode::AnyEventMouse => self.mouse_tracking == MouseTracking::AnyEvent,
+            DecMode::Utf8Mouse => self.mouse_encoding == MouseEncoding::Utf8,
+            DecMode::SgrMouse => self.mouse_encoding == MouseEncoding::Sgr,
+            DecMode::UrxvtMouse => self.mouse_encoding == MouseEncoding::Urxvt,
+            DecMode::FocusReporting => self.focus_reporting,
+            DecMode::BracketedPaste => self.bracketed_paste,
+            DecMode::SynchronizedUpdate => self.synchronized_update.is_some(),
+            DecMode::Unknown(_) => return None,
+        };
+
+        Some(ret)
+    }
+
+    fn set_mode(&mut self, mode: DecMode, enabled: bool) {
+        match mode {
+            DecMode::Origin => {
+                self.origin_mode = enabled;
+                self.cursor_home();
+            }
+            DecMode::AutoWrap => self.autowrap = enabled,
+            DecMode::ShowCursor => self.cursor_visible = enabled,
+            DecMode::X10Mouse => self.set_mouse_tracking(MouseTracking::X10, enabled),
+            DecMode::NormalMouse => self.set_mouse_tracking(MouseTracking::Normal, enabled),
+            DecMode::ButtonEventMouse => {
+                self.set_mouse_tracking(MouseTracking::ButtonEvent, enabled)
+            }
+            DecMode::AnyEventMouse => self.set_mouse_tracking(MouseTracking::AnyEvent, enabled),
+            DecMode::Utf8Mouse => self.set_mouse_encoding(MouseEncoding::Utf8, enabled),
+            DecMode::SgrMouse => self.set_mouse_encoding(MouseEncoding::Sgr, enabled),
+            DecMode::UrxvtMouse => self.set_mouse_encoding(MouseEncoding::Urxvt, enabled),
+            DecMode::FocusReporting => self.focus_reporting = enabled,
+            DecMode::BracketedPaste => self.bracketed_paste = enabled,
+            DecMode::SynchronizedUpdate => self.set_synchronized_update(enabled),
+            DecMode::Unknown(mode) => {
+                unhandled::record("emulator", "DEC mode", mode);
+            }
+        }
+    }
+
+    /// DECSTR, puts modes, charsets and rendition back to their defaults without touching the
+    /// screen contents or cursor position
+    fn soft_reset(&mut self) {
+        self.cursor_visible = true;
+        self.origin_mode = false;
+        self.saved_cursor = None;
+        self.buffer.set_scroll_region(0..self.buffer.height());
+        self.charsets = CharsetState::new();
+        self.cursor_pos.color = TerminalColor::Default;
+        self.cursor_pos.bold = false;
+    }
+
+    /// RIS, like a soft reset but also clears the screen, scrollback, tab stops and every other
+    /// mode the application may have changed
+    fn full_reset(&mut self) {
+        self.soft_reset();
+        self.buffer = TerminalBuffer::new(self.buffer.width(), self.buffer.height());
+        self.cursor_pos.x = 0;
+        self.cursor_pos.y = 0;
+        self.last_char = None;
+        self.palette = Palette::new();
+        self.mouse_tracking = MouseTracking::None;
+        self.mouse_encoding = MouseEncoding::Default;
+        self.last_mouse_cell = None;
+        self.bracketed_paste = false;
+        self.focus_reporting = false;
+        self.synchronized_update = None;
+        self.cursor_style = DEFAULT_CURSOR_STYLE;
+        self.autowrap = true;
+    }
+
+    /// Top left of the screen, or of the scroll region in origin mode
+    fn cursor_home(&mut self) {
+        self.cursor_pos.x = 0;
+        self.cursor_pos.y = if self.origin_mode {
+            self.buffer.scroll_region().start
+        } else {
+            0
+        };
+    }
+
+    /// Lines the cursor can be moved within vertically. Inside the scroll region movement stops
+    /// at the margins, outside it at the edges of the screen
+    fn cursor_bounds(&self) -> Range<usize> {
+        let region = self.buffer.scroll_region();
+        let y = self.cursor_pos.y;
+        let top = if y >= region.start { region.start } else { 0 };
+        let bottom = if y < region.end {
+            region.end
+        } else {
+            self.buffer.height()
+        };
+        top..bottom
+    }
+
+    fn move_cursor_up(&mut self, count: usize) {
+        let top = self.cursor_bounds().start;
+        self.cursor_pos.y = self.cursor_pos.y.saturating_sub(count).max(top);
+        self.cursor_pos.x = self.cursor_pos.x.min(self.buffer.width() - 1);
+    }
+
+    fn move_cursor_down(&mut self, count: usize) {
+        let bottom = self.cursor_bounds().end;
+        self.cursor_pos.y = self.cursor_pos.y.saturating_add(count).min(bottom - 1);
+        self.cursor_pos.x = self.cursor_pos.x.min(self.buffer.width() - 1);
+    }
+
+    fn save_cursor(&mut self) {
+        self.saved_cursor = Some(SavedCursor {
+            cursor_pos: self.cursor_pos.clone(),
+            origin_mode: self.origin_mode,
+            charsets: self.charsets.clone(),
+        });
+    }
+
+    /// Restores what DECSC saved, or the power on state if nothing was saved
+    fn restore_cursor(&mut self) {
+        match &self.saved_cursor {
+            Some(saved) => {
+                self.cursor_pos = saved.cursor_pos.clone();
+                self.origin_mode = saved.origin_mode;
+                self.charsets = saved.charsets.clone();
+            }
+            None => {
+                self.cursor_pos.x = 0;
+                self.cursor_pos.y = 0;
+                self.cursor_pos.bold = false;
+                self.cursor_pos.color = TerminalColor::Default;
+                self.origin_mode = false;
+                self.charsets = CharsetState::new();
+            }
+        }
+        // The screen may have shrunk since the save
+        self.cursor_pos.x = self.cursor_pos.x.min(self.buffer.width() - 1);
+        self.cursor_pos.y = self.cursor_pos.y.min(self.buffer.height() - 1);
+    }
+
+    /// Writes a printable character at the cursor
+    fn print(&mut self, c: char) {
+        if !self.autowrap {
+            self.cursor_pos.x = self.cursor_pos.x.min(self.buffer.width() - 1);
+        }
+        self.buffer.write_char(c, &mut self.cursor_pos);
+    }
+
+    fn handle_data(&mut self, data: &[u8]) {
+        // Most output is complete utf8, which can be handled without collecting the chars first
+        if self.utf8_pending.is_empty() {
+            if let Ok(data) = std::str::from_utf8(data) {
+                data.chars().for_each(|c| self.handle_char(c));
+                return;
             }
         }
 
-        if let Err(e) = ret {
-            if e != Errno::EAGAIN {
-                println!("Failed to read: {e}");
+        for c in decode_utf8(&mut self.utf8_pending, data) {
+            self.handle_char(c);
+        }
+    }
+
+    fn handle_char(&mut self, c: char) {
+        match c {
+            '\r' => self.cursor_pos.x = 0,
+            '\n' | '\x0b' | '\x0c' => self.buffer.line_feed(&mut self.cursor_pos),
+            '\x08' => {
+                // Backspace from a pending wrap lands on the second last column
+                let x = self.cursor_pos.x.min(self.buffer.width() - 1);
+                self.cursor_pos.x = x.saturating_sub(1);
+            }
+            '\t' => {
+                self.cursor_pos.x = self.buffer.next_tab_stop(self.cursor_pos.x, 1);
+            }
+            _ if c.is_control() => {}
+            _ => {
+                let c = self.charsets.translate(c);
+                self.print(c);
+                self.last_char = Some(c);
+            }
+        }
+    }
+
+    /// Drops a synchronized update the application has held open for too long
+    fn expire_synchronized_update(&mut self) {
+        if let Some(update) = &self.synchronized_update {
+            if update.started.elapsed() > SYNCHRONIZED_UPDATE_TIMEOUT {
+                log::debug!(target: "emulator", "Synchronized update timed out");
+                self.synchronized_update = None;
             }
         }
     }
 
-    pub fn data(&self) -> &[u8] {
-        &self.buf
+    /// Parses and applies output from the application. Sequences can be split across calls
+    pub fn feed(&mut self, incoming: &[u8]) {
+        // The parser borrows its buffers to us while it runs, so it has to be moved out of self
+        let mut parser = std::mem::take(&mut self.output_buf);
+        parser.advance(incoming, self);
+        self.output_buf = parser;
     }
 
+    /// Applies a single parsed sequence
+    fn apply(&mut self, segment: TerminalOutput) {
+        match segment {
+            TerminalOutput::Data(data) => self.handle_data(&data),
+            TerminalOutput::SetCursorPos { x, y } => {
+                if let Some(x) = x {
+                    self.cursor_pos.x = x.saturating_sub(1).min(self.buffer.width() - 1);
+                }
+                if let Some(y) = y {
+                    let lines = if self.origin_mode {
+                        self.buffer.scroll_region()
+                    } else {
+                        0..self.buffer.height()
+                    };
+                    self.cursor_pos.y = (lines.start + y.saturating_sub(1)).min(lines.end - 1);
+                }
+            }
+            TerminalOutput::CursorUp(count) => self.move_cursor_up(count),
+            TerminalOutput::CursorDown(count) => self.move_cursor_down(count),
+            TerminalOutput::CursorForward(count) => {
+                let x = self.cursor_pos.x.saturating_add(count);
+                self.cursor_pos.x = x.min(self.buffer.width() - 1);
+            }
+            TerminalOutput::CursorBackward(count) => {
+                let x = self.cursor_pos.x.min(self.buffer.width() - 1);
+                self.cursor_pos.x = x.saturating_sub(count);
+            }
+            TerminalOutput::CursorNextLine(count) => {
+                self.move_cursor_down(count);
+                self.cursor_pos.x = 0;
+            }
+            TerminalOutput::CursorPrevLine(count) => {
+                self.move_cursor_up(count);
+                self.cursor_pos.x = 0;
+            }
+            TerminalOutput::SetScrollRegion { top, bottom } => {
+                // Like xterm, a bottom past the screen stops at its last line. Regions have to
+                // be at least two lines, anything else is ignored
+                let height = self.buffer.height();
+                let bottom = bottom.unwrap_or(height).min(height);
+                if top < bottom {
+                    self.buffer.set_scroll_region(top - 1..bottom);
+                    self.cursor_home();
+                }
+            }
+            TerminalOutput::Index => self.buffer.line_feed(&mut self.cursor_pos),
+            TerminalOutput::NextLine => {
+                self.cursor_pos.x = 0;
+                self.buffer.line_feed(&mut self.cursor_pos);
+            }
+            TerminalOutput::ReverseIndex => self.buffer.reverse_index(&mut self.cursor_pos),
+            TerminalOutput::SaveCursor => self.save_cursor(),
+            TerminalOutput::RestoreCursor => self.restore_cursor(),
+            TerminalOutput::ScrollUp(count) => self.buffer.scroll_region_up(count),
+            TerminalOutput::ScrollDown(count) => self.buffer.scroll_region_down(count),
+            TerminalOutput::InsertLines(count) => {
+                self.buffer.insert_lines(self.cursor_pos.y, count);
+                self.cursor_pos.x = 0;
+            }
+            TerminalOutput::DeleteLines(count) => {
+                self.buffer.delete_lines(self.cursor_pos.y, count);
+                self.cursor_pos.x = 0;
+            }
+            TerminalOutput::InsertCharacters(count) => {
+                self.buffer.insert_chars(&self.cursor_pos, count);
+            }
+            TerminalOutput::DeleteCharacters(count) => {
+                self.buffer.delete_chars(&self.cursor_pos, count);
+            }
+            TerminalOutput::EraseCharacters(count) => {
+                self.buffer.erase_chars(&self.cursor_pos, count);
+            }
+            TerminalOutput::ClearLineForwards => {
+                self.buffer.clear_line_forwards(&self.cursor_pos);
+            }
+            TerminalOutput::ClearLineBackwards => {
+                self.buffer.clear_line_backwards(&self.cursor_pos);
+            }
+            TerminalOutput::ClearLine => self.buffer.clear_line(self.cursor_pos.y),
+            TerminalOutput::ClearForwards => {
+                self.buffer.clear_forwards(&self.cursor_pos);
+            }
+            TerminalOutput::ClearBackwards => {
+                self.buffer.clear_backwards(&self.cursor_pos);
+            }
+            TerminalOutput::ClearAll => {
+                self.buffer.clear_all();
+            }
+            TerminalOutput::Sgr(sgr) => {
+                //TODO: should be match?
+                if let Some(color) = TerminalColor::from_sgr(sgr) {
+                    self.cursor_pos.color = color;
+                } else if sgr == SelectGraphicRendition::Reset {
+                    self.cursor_pos.color = TerminalColor::Default;
+                    self.cursor_pos.bold = false; 
+                } else if sgr == SelectGraphicRendition::Bold {
+                    self.cursor_pos.bold = true;
+                } else {
+                    unhandled::record("emulator", "SGR", sgr.code());
+                }
+            }
+            TerminalOutput::SetPaletteColor { index, color } => {
+                self.palette.colors[index] = color;
+            }
+            TerminalOutput::QueryPaletteColor(index) => {
+                let response = format!(
+                    "\x1b]4;{index};{}\x1b\\",
+                    format_color_spec(self.palette.colors[index])
+                );
+                self.respond(response.as_bytes());
+            }
+            TerminalOutput::ResetPaletteColor(Some(index)) => {
+                self.palette.reset_color(index);
+            }
+            TerminalOutput::ResetPaletteColor(None) => {
+                self.palette.reset_colors();
+            }
+            TerminalOutput::SetDynamicColor { target, color } => {
+                self.palette.set_dynamic_color(target, color);
+            }
+            TerminalOutput::QueryDynamicColor(target) => {
+                let response = format!(
+                    "\x1b]{};{}\x1b\\",
+                    target.osc_code(),
+                    format_color_spec(self.palette.dynamic_color(target))
+                );
+                self.respond(response.as_bytes());
+            }
+            TerminalOutput::ResetDynamicColor(target) => {
+                self.palette.reset_dynamic_color(target);
+            }
+            TerminalOutput::RequestStatusReport => {
+                self.respond(b"\x1b[0n");
+            }
+            TerminalOutput::RequestCursorPosition { extended } => {
+                let marker = if extended { "?" } else { "" };
+                // A pending wrap still reports the last column, origin mode reports the row
+                // within the scroll region
+                let top = if self.origin_mode {
+                    self.buffer.scroll_region().start
+                } else {
+                    0
+                };
+                let response = format!(
+                    "\x1b[{marker}{};{}R",
+                    self.cursor_pos.y.saturating_sub(top) + 1,
+                    self.cursor_pos.x.min(self.buffer.width() - 1) + 1
+                );
+                self.respond(response.as_bytes());
+            }
+            TerminalOutput::RequestDeviceAttributes => {
+                self.respond(DEVICE_ATTRIBUTES_RESPONSE);
+            }
+            TerminalOutput::RequestSecondaryDeviceAttributes => {
+                let response = format!("\x1b[>1;{};0c", terminal_version_number());
+                self.respond(response.as_bytes());
+            }
+            TerminalOutput::RequestTerminalVersion => {
+                let response = format!(
+                    "\x1bP>|{} {}\x1b\\",
+                    env!("CARGO_PKG_NAME"),
+                    env!("CARGO_PKG_VERSION")
+                );
+                self.respond(response.as_bytes());
+            }
+            TerminalOutput::SetMode(mode) => self.set_mode(mode, true),
+            TerminalOutput::ResetMode(mode) => self.set_mode(mode, false),
+            TerminalOutput::RequestMode(mode) => {
+                // DECRPM: 0 = not recognized, 1 = set, 2 = reset
+                let state = match self.mode_state(mode) {
+                    None => 0,
+                    Some(true) => 1,
+                    Some(false) => 2,
+                };
+                let response = format!("\x1b[?{};{state}$y", mode.to_usize());
+                self.respond(response.as_bytes());
+            }
+            TerminalOutput::SetCursorStyle(style) => self.cursor_style = style,
+            TerminalOutput::DesignateCharset { slot, charset } => {
+                self.charsets.designate(slot, charset);
+            }
+            TerminalOutput::LockingShift(slot) => self.charsets.locking_shift(slot),
+            TerminalOutput::SingleShift(slot) => self.charsets.single_shift(slot),
+            TerminalOutput::SetTabStop => self.buffer.set_tab_stop(self.cursor_pos.x),
+            TerminalOutput::ClearTabStop => {
+                self.buffer.clear_tab_stop(self.cursor_pos.x);
+            }
+            TerminalOutput::ClearAllTabStops => self.buffer.clear_all_tab_stops(),
+            TerminalOutput::ForwardTab(count) => {
+                self.cursor_pos.x = self.buffer.next_tab_stop(self.cursor_pos.x, count);
+            }
+            TerminalOutput::BackwardTab(count) => {
+                self.cursor_pos.x = self.buffer.prev_tab_stop(self.cursor_pos.x, count);
+            }
+            TerminalOutput::RepeatCharacter(count) => {
+                let Some(c) = self.last_char else {
+                    return;
+                };
+                // Anything past a full screen would be overwritten anyway
+                let count = count.min(self.buffer.width() * self.buffer.height());
+                for _ in 0..count {
+                    self.print(c);
+                }
+            }
+            TerminalOutput::ScrollLeft(count) => self.buffer.delete_columns(0, count),
+            TerminalOutput::ScrollRight(count) => self.buffer.insert_columns(0, count),
+            // DECIC and DECDC do nothing when the cursor is outside the scroll region
+            TerminalOutput::InsertColumns(count) => {
+                if self.buffer.scroll_region().contains(&self.cursor_pos.y) {
+                    self.buffer.insert_columns(self.cursor_pos.x, count);
+                }
+            }
+            TerminalOutput::DeleteColumns(count) => {
+                if self.buffer.scroll_region().contains(&self.cursor_pos.y) {
+                    self.buffer.delete_columns(self.cursor_pos.x, count);
+                }
+            }
+            TerminalOutput::FullReset => self.full_reset(),
+            TerminalOutput::SoftReset => self.soft_reset(),
+            TerminalOutput::ScreenAlignmentTest => {
+                self.buffer.fill('E');
+                self.buffer.set_scroll_region(0..self.buffer.height());
+                self.origin_mode = false;
+                self.cursor_pos.x = 0;
+                self.cursor_pos.y = 0;
+            }
+            TerminalOutput::Invalid => {}
+        }
+    }
+
+    /// Writes a line of our own below the output, e.g. to say that the child exited
+    pub fn show_message(&mut self, message: &str) {
+        if self.cursor_pos.x != 0 {
+            self.handle_data(b"\r\n");
+        }
+        self.handle_data(message.as_bytes());
+    }
+
+    /// Resizes the screen, at least 1x1
+    pub fn set_win_size(&mut self, width: usize, height: usize) {
+        let width = width.clamp(1, u16::MAX as usize);
+        let height = height.clamp(1, u16::MAX as usize);
+        if width == self.buffer.width() && height == self.buffer.height() {
+            return;
+        }
+
+        self.buffer.resize(width, height, &mut self.cursor_pos);
+    }
+
+    pub fn screen(&self) -> &TerminalBuffer {
+        &self.buffer
+    }
+
+    /// Visible screen as text, one line per row
+    pub fn data(&self) -> Vec<u8> {
+        let mut ret = String::new();
+        for (i, line) in self.buffer.trimmed_lines().enumerate() {
+            if i > 0 {
+                ret.push('\n');
+            }
+            ret.extend(line.iter().map(|cell| cell.c));
+        }
+        ret.into_bytes()
+    }
+
+    /// Formatting of the bytes returned by [`EmulatorCore::data`], one tag per run of cells that
+    /// look the same. The last tag runs to `usize::MAX`
     pub fn format_data(&self) -> Vec<FormatTag> {
-        self.format_tracker.tags()
+        let mut tags = vec![FormatTag {
+            start: 0,
+            end: usize::MAX,
+            color: TerminalColor::Default,
+            bold: false,
+        }];
+        let mut pos = 0;
+
+        for (i, line) in self.buffer.trimmed_lines().enumerate() {
+            // Newlines take the format of whatever comes before them
+            if i > 0 {
+                pos += 1;
+            }
+
+            for cell in line {
+                let last = tags.last_mut().expect("tags start with the default");
+                if last.color != cell.color || last.bold != cell.bold {
+                    if last.start == pos {
+                        // Only the default tag can still be empty
+                        last.color = cell.color;
+                        last.bold = cell.bold;
+                    } else {
+                        last.end = pos;
+                        tags.push(FormatTag {
+                            start: pos,
+                            end: usize::MAX,
+                            color: cell.color,
+                            bold: cell.bold,
+                        });
+                    }
+                }
+                pos += cell.c.len_utf8();
+            }
+        }
+
+        tags
     }
 
     pub fn cursor_pos(&self) -> CursorState {
         self.cursor_pos.clone()
     }
+
+    /// The screen to show while a synchronized update is open, None if the live state should be
+    /// shown
+    pub fn synchronized_snapshot(&self) -> Option<&TerminalSnapshot> {
+        // Nothing may be read to clear a timed out update, so check the time here as well
+        self.synchronized_update
+            .as_ref()
+            .filter(|update| update.started.elapsed() <= SYNCHRONIZED_UPDATE_TIMEOUT)
+            .map(|update| &update.snapshot)
+    }
+
+    /// Copy of the screen to show, so it can be drawn without holding on to the emulator
+    pub fn snapshot(&self) -> TerminalSnapshot {
+        self.synchronized_snapshot()
+            .cloned()
+            .unwrap_or_else(|| self.live_snapshot())
+    }
+
+    fn live_snapshot(&self) -> TerminalSnapshot {
+        TerminalSnapshot {
+            data: self.data(),
+            format_data: self.format_data(),
+            cursor_pos: self.cursor_pos(),
+        }
+    }
+
+    /// Time left until an open synchronized update is abandoned
+    pub fn synchronized_update_remaining(&self) -> Option<Duration> {
+        self.synchronized_update
+            .as_ref()
+            .map(|update| SYNCHRONIZED_UPDATE_TIMEOUT.saturating_sub(update.started.elapsed()))
+    }
+
+    pub fn cursor_style(&self) -> CursorStyle {
+        self.cursor_style
+    }
+
+    pub fn cursor_visible(&self) -> bool {
+        self.cursor_visible
+    }
+
+    pub fn palette(&self) -> &Palette {
+        &self.palette
+    }
+
+    /// What to tell the application when the window gains or loses focus, if it asked to know
+    pub fn focus_report(&self, focused: bool) -> Option<&'static [u8]> {
+        if !self.focus_reporting {
+            return None;
+        }
+
+        if focused {
+            Some(b"\x1b[I")
+        } else {
+            Some(b"\x1b[O")
+        }
+    }
+
+    pub fn bracketed_paste(&self) -> bool {
+        self.bracketed_paste
+    }
+
+    /// Pasted text the way the application should receive it
+    pub fn paste_data(&self, text: &str) -> Vec<u8> {
+        let sanitized = sanitize_paste(text);
+        if self.bracketed_paste {
+            [&b"\x1b[200~"[..], &sanitized, b"\x1b[201~"].concat()
+        } else {
+            sanitized
+        }
+    }
+
+    pub fn mouse_tracking(&self) -> MouseTracking {
+        self.mouse_tracking
+    }
+
+    /// The report for a mouse event, if the current tracking mode asks for one
+    pub fn mouse_report(&mut self, event: MouseEvent) -> Option<Vec<u8>> {
+        let cell = (event.x, event.y);
+        if let MouseEventKind::Motion(_) = event.kind {
+            // Pointer motion arrives per pixel, but applications only care about cells
+            if self.last_mouse_cell == Some(cell) {
+                return None;
+            }
+        }
+
+        let encoded = encode_mouse_event(self.mouse_tracking, self.mouse_encoding, &event)?;
+        self.last_mouse_cell = Some(cell);
+        Some(encoded)
+    }
 }
 
-#[cfg(test)]
-mod test {
-    use super::*;
+impl Perform for EmulatorCore {
+    fn print(&mut self, data: &[u8]) {
+        self.handle_data(data);
+    }
 
-    #[test]
-    fn test_cursor_data_insert() {
-        let mut buf = Vec::new();
-        insert_data_at_position(b"asdf", 0, &mut buf);
-        assert_eq!(buf, b"asdf");
+    fn execute(&mut self, byte: u8) {
+        match byte {
+            // SO and SI
+            0x0e => self.charsets.locking_shift(1),
+            0x0f => self.charsets.locking_shift(0),
+            // C1 controls do the same as ESC followed by byte - 0x40
+            0x80..=0x9f => self.esc_dispatch(&[], byte - 0x40),
+            _ => self.handle_data(&[byte]),
+        }
+    }
+
+    fn csi_dispatch(&mut self, csi: &Csi, ignore: bool) {
+        dispatch_csi(csi, ignore, &mut |output| self.apply(output));
+    }
+
+    fn esc_dispatch(&mut self, intermediates: &[u8], final_byte: u8) {
+        self.apply(dispatch_esc(intermediates, final_byte));
+    }
+
+    fn osc_dispatch(&mut self, data: &[u8]) {
+        dispatch_osc(data, &mut |output| self.apply(output));
+    }
+}
 
-        insert_data_at_position(b"123", 0, &mut buf);
-        assert_eq!(buf, b"123f");
+/// An emulator attached to a child on a pty
+pub struct TerminalEmulator {
+    core: EmulatorCore,
+    pty: Pty,
+    read_buf: Vec<u8>,
+}
 
-        insert_data_at_position(b"xyzw", 4, &mut buf);
-        assert_eq!(buf, b"123fxyzw");
+impl TerminalEmulator {
+    pub fn new(config: &SessionConfig) -> Result<TerminalEmulator, TerminalError> {
+        let term = terminfo::ensure_installed();
+        let pty = Pty::spawn(DEFAULT_WIDTH, DEFAULT_HEIGHT, term, config)?;
+        Ok(TerminalEmulator::with_pty(pty))
+    }
+
+    /// Attaches to a pty that is already open, e.g. one end of [`Pty::pair`]
+    pub fn with_pty(pty: Pty) -> TerminalEmulator {
+        TerminalEmulator {
+            core: EmulatorCore::new(DEFAULT_WIDTH, DEFAULT_HEIGHT),
+            pty,
+            read_buf: vec![0; READ_BUF_SIZE],
+        }
+    }
+
+    pub fn core(&self) -> &EmulatorCore {
+        &self.core
+    }
+
+    pub fn core_mut(&mut self) -> &mut EmulatorCore {
+        &mut self.core
+    }
 
-        insert_data_at_position(b"asdf", 2, &mut buf);
-        assert_eq!(buf, b"12asdfzw");
+    pub fn write(&mut self, to_write: &[u8]) -> Result<(), TerminalError> {
+        self.pty.write(to_write)
     }
 
+    /// Processes everything the child has written so far. Returns an event once the child exits
+    pub fn read(&mut self) -> Option<TerminalEvent> {
+        if let Err(e) = self.pty.flush() {
+            log::warn!(target: "pty", "{e}");
+        }
+
+        let mut total_read = 0;
+        while total_read < READ_BATCH_LIMIT {
+            let Some(read_size) = self.pty.read(&mut self.read_buf) else {
+                break;
+            };
+
+            total_read += read_size;
+            self.core.feed(&self.read_buf[..read_size]);
+
+            // Replies go out once per batch so that they stay in the same order as the requests
+            let responses = self.core.take_responses();
+            if let Err(e) = self.pty.write(&responses) {
+                log::warn!(target: "pty", "{e}");
+            }
+        }
+
+        self.core.expire_synchronized_update();
+
+        let status = self.pty.try_reap_child()?;
+        Some(TerminalEvent::ChildExited { status })
+    }
+
+    /// Whether the other end of the pty has been closed, after which nothing more can be read
+    pub fn hung_up(&self) -> bool {
+        self.pty.hung_up()
+    }
+
+    /// Whether some input is waiting for the child to make room for it, [`TerminalEmulator::read`]
+    /// sends it once there is
+    pub fn has_unwritten(&self) -> bool {
+        self.pty.has_unwritten()
+    }
+
+    /// Resizes the screen and tells the application about the new size
+    pub fn set_win_size(&mut self, width: usize, height: usize) {
+        let screen = self.core.screen();
+        let old_size = (screen.width(), screen.height());
+        self.core.set_win_size(width, height);
+
+        let screen = self.core.screen();
+        if (screen.width(), screen.height()) != old_size {
+            self.pty.set_win_size(screen.width(), screen.height());
+        }
+    }
+
+    /// Tells the application that the window gained or lost focus, if it asked to know
+    pub fn focus_changed(&mut self, focused: bool) -> Result<(), TerminalError> {
+        match self.core.focus_report(focused) {
+            Some(report) => self.pty.write(report),
+            None => Ok(()),
+        }
+    }
+
+    pub fn paste(&mut self, text: &str) -> Result<(), TerminalError> {
+        let data = self.core.paste_data(text);
+        self.pty.write(&data)
+    }
+
+    /// Reports a mouse event to the application if the current tracking mode asks for it
+    pub fn mouse_event(&mut self, event: MouseEvent) -> Result<(), TerminalError> {
+        match self.core.mouse_report(event) {
+            Some(report) => self.pty.write(&report),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::os::fd::AsRawFd;
+
+    use super::*;
+
     #[test]
-    fn basic_color_tracker_test() {
-        let mut format_tracker = FormatTracker::new();
-        let mut cursor_state = CursorState {
-            x:0,
-            y:0,
-            color: TerminalColor::Default,
-            bold: false
+    fn test_format_data() {
+        let tag = |start, end, color, bold| FormatTag {
+            start,
+            end,
+            color,
+            bold,
         };
 
-        cursor_state.color = TerminalColor::Yellow;
-        format_tracker.push_range(&cursor_state, 3..10);
-        let tags = format_tracker.tags();
+        let mut emu = EmulatorCore::new(10, 3);
         assert_eq!(
-            tags,
-            &[
-                FormatTag { start:0, end:3, color: TerminalColor::Default, bold: false },
-                FormatTag { start:3, end:10, color: TerminalColor::Yellow, bold: false },
-                FormatTag { start:10, end:usize::MAX, color: TerminalColor::Default, bold: false },
-            ]
+            emu.format_data(),
+            &[tag(0, usize::MAX, TerminalColor::Default, false)]
         );
 
-        cursor_state.color = TerminalColor::Blue;
-        format_tracker.push_range(&cursor_state, 5..7);
-        let tags = format_tracker.tags();
+        emu.feed(b"\x1b[33mab\x1b[1mc\x1b[0md\r\n\x1b[1mef\x1b[34m\xc3\xa9");
+        assert_eq!(emu.data(), "abcd\nefé\n".as_bytes());
         assert_eq!(
-            tags,
+            emu.format_data(),
             &[
-                FormatTag { start:0, end:3, color: TerminalColor::Default, bold: false },
-                FormatTag { start:3, end:5, color: TerminalColor::Yellow, bold: false },
-                FormatTag { start:5, end:7, color: TerminalColor::Blue, bold: false },
-                FormatTag { start:7, end:10, color: TerminalColor::Yellow, bold: false },
-                FormatTag { start:10, end:usize::MAX, color: TerminalColor::Default, bold: false },
+                tag(0, 2, TerminalColor::Yellow, false),
+                tag(2, 3, TerminalColor::Yellow, true),
+                tag(3, 5, TerminalColor::Default, false),
+                tag(5, 7, TerminalColor::Default, true),
+                tag(7, usize::MAX, TerminalColor::Blue, true),
             ]
         );
+    }
+
+    #[test]
+    fn test_terminal_version_number() {
+        // Two digits each for the minor and patch versions
+        let [major, minor, patch] = [
+            env!("CARGO_PKG_VERSION_MAJOR"),
+            env!("CARGO_PKG_VERSION_MINOR"),
+            env!("CARGO_PKG_VERSION_PATCH"),
+        ]
+        .map(|v| v.parse::<usize>().unwrap());
+        let version = major * 10000 + minor * 100 + patch;
+        assert_eq!(terminal_version_number(), version);
+
+        let mut emu = EmulatorCore::new(DEFAULT_WIDTH, DEFAULT_HEIGHT);
+        emu.feed(b"\x1b[>c\x1b[>0c");
+        let reply = format!("\x1b[>1;{version};0c");
+        assert_eq!(emu.take_responses(), reply.repeat(2).as_bytes());
+    }
+
+    #[test]
+    fn test_sanitize_paste() {
+        assert_eq!(sanitize_paste("echo hi"), b"echo hi");
+        assert_eq!(sanitize_paste("a\nb\r\nc\td"), b"a\rb\rc\td");
+        assert_eq!(sanitize_paste("x\x1b[201~rm -rf ~\n"), b"x[201~rm -rf ~\r");
+        assert_eq!(sanitize_paste("\u{9b}201~\x07é"), "201~é".as_bytes());
+    }
+
+    #[test]
+    fn test_decode_utf8() {
+        let mut pending = Vec::new();
+        assert_eq!(decode_utf8(&mut pending, b"a\xe2\x94"), ['a']);
+        assert_eq!(pending, b"\xe2\x94");
+        assert_eq!(decode_utf8(&mut pending, b"\x80b"), ['─', 'b']);
+        assert!(pending.is_empty());
+        assert_eq!(decode_utf8(&mut pending, b"\xffc"), ['\u{fffd}', 'c']);
+    }
+
+    #[test]
+    fn test_hostile_output() {
+        let hostile: &[&[u8]] = &[
+            b"\x1b[99999;99999H\x1b[0;0H\x08\x08\x08",
+            b"x\x1b[65535b\x1b[99999@\x1b[99999P\x1b[99999X",
+            b"\x1b[99999 @\x1b[99999 A\x1b[99999'}\x1b[99999'~",
+            b"\x1b[99999I\x1b[99999Z\x1b[99999g\x1b[3g\t\t",
+            b"\x1b[99999A\x1b[99999B\x1b[99999C\x1b[99999D\x1b[99999d\x1b[99999G",
+            b"\x1b[99999J\x1b[99999K\x1b[1J\x1b[1K\x1b[2J",
+            b"\x1b#8\x1bc\x1b[!p\x1b7\x1b8",
+            b"\x1b]4;99999;rgb:ff/ff/ff\x07\x1b]4;-1;?\x07\x1b]10;?;?;?;?;?;?;?;?;?;?\x07",
+            b"\x1b]11;rgb:fffff/0/0\x1b\\\x1b]104;a;b;c\x07",
+            b"\x1b[38;2;999;999;999m\x1b[38:5:99999m\x1b[48;5m\x1b[38;2m\x1b[4:9m",
+            b"\xff\xfe\xe2\x94\x1b(0qqq\x0e\x0f\x1b)0\x1b*0\x1b+0\x1bn\x1bo\x1bN\x1bO",
+            b"\x1b[?1;1000;1006;2004;2026;99999h\x1b[?99999$p\x1b[c\x1b[>c\x1b[6n",
+            b"\x1bP1;2;3|unterminated dcs \x9c\x1b]0;unterminated osc",
+            b"\x1b[1;2;3;4;5;6;7;8;9;10;11;12;13;14;15;16;17;18;19;20;21;22;23;24;25;26;27;28;29;30;31;32;33;34m",
+        ];
+
+        // Deterministic pseudo random bytes, biased towards the ones that start or end sequences
+        let mut state = 0x2545f491u32;
+        let mut random = || {
+            state = state.wrapping_mul(1664525).wrapping_add(1013904223);
+            state >> 8
+        };
+        let special = b"\x1b[;:?>!$' 0123456789\x07\x9b\x9d\x9c";
+        let mut noise = Vec::new();
+        for _ in 0..20000 {
+            let byte = match random() % 4 {
+                0 => special[random() as usize % special.len()],
+                _ => random() as u8,
+            };
+            noise.push(byte);
+        }
+
+        let mut input = hostile.concat();
+        input.extend_from_slice(&noise);
+        fuzz::check_chunked_parse(&input);
+
+        let mut emulator = EmulatorCore::new(DEFAULT_WIDTH, DEFAULT_HEIGHT);
+        for (width, height) in [(80, 24), (1, 1), (0, 0), (3, 200)] {
+            emulator.set_win_size(width, height);
+            fuzz::check_emulator(&mut emulator, &input);
+        }
+    }
+
+    #[test]
+    fn test_headless() {
+        let mut emu = EmulatorCore::new(20, 5);
+        emu.feed(b"junk\x1b[2J\x1b[3;5Hhi");
+        assert_eq!(emu.screen().line(0).as_deref(), Some(""));
+        assert_eq!(emu.screen().line(2).as_deref(), Some("    hi"));
+        assert_eq!(emu.screen().line(5), None);
+        assert_eq!(emu.screen().cells(4).map(<[_]>::len), Some(20));
+        assert!(emu.screen().cells(5).is_none());
+
+        emu.feed(b"\x1b[6n\x1b[c");
+        assert_eq!(emu.take_responses(), b"\x1b[3;7R\x1b[?62;22c");
+        assert!(emu.take_responses().is_empty());
+    }
+
+    fn screen_text(emu: &EmulatorCore) -> Vec<String> {
+        emu.screen()
+            .trimmed_lines()
+            .map(|line| line.iter().map(|cell| cell.c).collect())
+            .collect()
+    }
+
+    fn cursor(emu: &EmulatorCore) -> (usize, usize) {
+        (emu.cursor_pos().x, emu.cursor_pos().y)
+    }
+
+    #[test]
+    fn test_cursor_movement() {
+        let mut emu = EmulatorCore::new(10, 6);
+
+        // Inside the scroll region vertical movement stops at the margins
+        emu.feed(b"\x1b[2;4r\x1b[3;5H\x1b[9A");
+        assert_eq!(cursor(&emu), (4, 1));
+        emu.feed(b"\x1b[9B");
+        assert_eq!(cursor(&emu), (4, 3));
+
+        // Outside it at the edges of the screen
+        emu.feed(b"\x1b[6;1H\x1b[9B");
+        assert_eq!(cursor(&emu), (0, 5));
+        emu.feed(b"\x1b[1;1H\x1b[9B");
+        assert_eq!(cursor(&emu), (0, 3));
+
+        emu.feed(b"\x1b[1;1H\x1b[20C");
+        assert_eq!(cursor(&emu), (9, 0));
+        emu.feed(b"\x1b[3D");
+        assert_eq!(cursor(&emu), (6, 0));
+        emu.feed(b"\x1b[2E");
+        assert_eq!(cursor(&emu), (0, 2));
+        emu.feed(b"\x1b[5G\x1b[F");
+        assert_eq!(cursor(&emu), (0, 1));
+        emu.feed(b"\x1b[5d");
+        assert_eq!(cursor(&emu), (0, 4));
+    }
+
+    #[test]
+    fn test_index() {
+        let mut emu = EmulatorCore::new(5, 4);
+        emu.feed(b"a\r\nb\r\nc\r\nd\x1b[2;3r");
+
+        // IND scrolls at the bottom margin, RI at the top one
+        emu.feed(b"\x1b[3;1H\x1bD");
+        assert_eq!(screen_text(&emu), ["a", "c", "", "d"]);
+        assert_eq!(cursor(&emu), (0, 2));
+        emu.feed(b"\x1bM\x1bM");
+        assert_eq!(screen_text(&emu), ["a", "", "c", "d"]);
+        assert_eq!(cursor(&emu), (0, 1));
+
+        emu.feed(b"\x1b[3GNEL\x1bE");
+        assert_eq!(cursor(&emu), (0, 2));
+
+        emu.feed(b"\x1b[S");
+        assert_eq!(screen_text(&emu), ["a", "c", "", "d"]);
+        emu.feed(b"\x1b[2T");
+        assert_eq!(screen_text(&emu), ["a", "", "", "d"]);
+    }
+
+    #[test]
+    fn test_scroll_region_bounds() {
+        let mut emu = EmulatorCore::new(5, 4);
+        emu.feed(b"a\r\nb\r\nc\r\nd");
+
+        emu.feed(b"\x1b[3;3H\x1b[4;2r\x1b[3;3r");
+        assert_eq!(cursor(&emu), (2, 2));
+
+        // The bottom is clamped to the screen rather than the region being ignored
+        emu.feed(b"\x1b[2;99r");
+        assert_eq!(cursor(&emu), (0, 0));
+        emu.feed(b"\x1b[4;1H\n");
+        assert_eq!(screen_text(&emu), ["a", "c", "d", ""]);
+    }
+
+    #[test]
+    fn test_origin_mode() {
+        let mut emu = EmulatorCore::new(10, 6);
+        emu.feed(b"\x1b[3;5r\x1b[?6h");
+        assert_eq!(cursor(&emu), (0, 2));
+
+        // Rows count from the top margin and stay within the region
+        emu.feed(b"\x1b[2;4H\x1b[6n");
+        assert_eq!(cursor(&emu), (3, 3));
+        assert_eq!(emu.take_responses(), b"\x1b[2;4R");
+        emu.feed(b"\x1b[9;1H");
+        assert_eq!(cursor(&emu), (0, 4));
+
+        emu.feed(b"\x1b[?6$p\x1b[?6l");
+        assert_eq!(emu.take_responses(), b"\x1b[?6;1$y");
+        assert_eq!(cursor(&emu), (0, 0));
+    }
+
+    #[test]
+    fn test_save_restore_cursor() {
+        let mut emu = EmulatorCore::new(10, 4);
+        emu.feed(b"\x1b[2;3H\x1b[1;31m\x1b7\x1b[m\x1b[4;5H\x1b8x");
+        let cell = &emu.screen().cells(1).unwrap()[2];
+        assert_eq!(cell.c, 'x');
+        assert!(cell.bold);
+        assert_eq!(cell.color, TerminalColor::Red);
+        assert_eq!(cursor(&emu), (3, 1));
+
+        // Nothing saved restores the power on state
+        let mut emu = EmulatorCore::new(10, 4);
+        emu.feed(b"\x1b[3;3H\x1b[u");
+        assert_eq!(cursor(&emu), (0, 0));
+    }
+
+    #[test]
+    fn test_autowrap() {
+        let mut emu = EmulatorCore::new(4, 2);
+        emu.feed(b"\x1b[?7labcdef");
+        assert_eq!(screen_text(&emu), ["abcf", ""]);
+
+        emu.feed(b"\x1b[?7hgh");
+        assert_eq!(screen_text(&emu), ["abcf", "gh"]);
+    }
+
+    /// The pty hands data over asynchronously, so keep reading until it has arrived
+    fn read_until(emulator: &mut TerminalEmulator, done: impl Fn(&TerminalEmulator) -> bool) {
+        let start = Instant::now();
+        while !done(emulator) {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            emulator.read();
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn test_fake_pty() {
+        let (pty, app) = Pty::pair(DEFAULT_WIDTH, DEFAULT_HEIGHT).unwrap();
+        let mut emulator = TerminalEmulator::with_pty(pty);
+
+        nix::unistd::write(app.as_raw_fd(), b"hello\r\n\x1b[6n").unwrap();
+        read_until(&mut emulator, |emulator| {
+            emulator.core().screen().line(0).as_deref() == Some("hello")
+        });
 
-        cursor_state.color = TerminalColor::Green;
-        format_tracker.push_range(&cursor_state, 7..9);
-        let tags = format_tracker.tags();
+        // The reply to the request arrives before anything typed afterwards
+        emulator.write(b"ls\r").unwrap();
+        let mut buf = [0; 64];
+        let mut received = Vec::new();
+        while received.len() < 9 {
+            let len = nix::unistd::read(app.as_raw_fd(), &mut buf).unwrap();
+            received.extend_from_slice(&buf[..len]);
+        }
+        assert_eq!(received, b"\x1b[2;1Rls\r");
+
+        drop(app);
+        read_until(&mut emulator, TerminalEmulator::hung_up);
+    }
+
+    #[test]
+    fn test_write_backlog() {
+        let (pty, app) = Pty::pair(DEFAULT_WIDTH, DEFAULT_HEIGHT).unwrap();
+        let mut emulator = TerminalEmulator::with_pty(pty);
+
+        // More than the pty holds, the rest waits rather than blocking until the app reads
+        let input = (0..1 << 20)
+            .map(|i| b'a' + (i % 26) as u8)
+            .collect::<Vec<_>>();
+        emulator.write(&input).unwrap();
+        assert!(emulator.has_unwritten());
+
+        let mut buf = [0; 4096];
+        let mut received = Vec::new();
+        while received.len() < input.len() {
+            let len = nix::unistd::read(app.as_raw_fd(), &mut buf).unwrap();
+            received.extend_from_slice(&buf[..len]);
+            emulator.read();
+        }
+        assert_eq!(received, input);
+        assert!(!emulator.has_unwritten());
+    }
+
+    #[test]
+    fn test_spawn() {
+        let config = SessionConfig {
+            program: "sh".into(),
+            args: vec!["-c".to_string(), "echo \"$TERM $EDITOR\"; pwd".to_string()],
+            env: vec![("EDITOR".to_string(), "vim".to_string())],
+            cwd: Some("/".into()),
+            ..SessionConfig::default()
+        };
+        let pty = Pty::spawn(DEFAULT_WIDTH, DEFAULT_HEIGHT, "xterm", &config).unwrap();
+        let mut emulator = TerminalEmulator::with_pty(pty);
+
+        read_until(&mut emulator, TerminalEmulator::hung_up);
+        let screen = emulator.core().screen();
+        assert_eq!(screen.line(0).as_deref(), Some("xterm vim"));
+        assert_eq!(screen.line(1).as_deref(), Some("/"));
+
+        let config = SessionConfig {
+            program: "/nonexistent".into(),
+            cwd: Some("/nonexistent".into()),
+            ..SessionConfig::default()
+        };
+        let pty = Pty::spawn(DEFAULT_WIDTH, DEFAULT_HEIGHT, "xterm", &config).unwrap();
+        let mut emulator = TerminalEmulator::with_pty(pty);
+
+        read_until(&mut emulator, TerminalEmulator::hung_up);
+        let screen = emulator.core().screen();
         assert_eq!(
-            tags,
-            &[
-                FormatTag { start:0, end:3, color: TerminalColor::Default, bold: false },
-                FormatTag { start:3, end:5, color: TerminalColor::Yellow, bold: false },
-                FormatTag { start:5, end:7, color: TerminalColor::Blue, bold: false },
-                FormatTag { start:7, end:9, color: TerminalColor::Green, bold: false },
-                FormatTag { start:9, end:10, color: TerminalColor::Yellow, bold: false },
-                FormatTag { start:10, end:usize::MAX, color: TerminalColor::Default, bold: false },
-            ]
+            screen.line(0).as_deref(),
+            Some("Failed to change directory to /nonexistent")
         );
-
-        cursor_state.color = TerminalColor::Red;
-        format_tracker.push_range(&cursor_state, 6..11);
-        let tags = format_tracker.tags();
         assert_eq!(
-            tags,
-            &[
-                FormatTag { start:0, end:3, color: TerminalColor::Default, bold: false },
-                FormatTag { start:3, end:5, color: TerminalColor::Yellow, bold: false },
-                FormatTag { start:5, end:6, color: TerminalColor::Blue, bold: false },
-                FormatTag { start:6, end:11, color: TerminalColor::Red, bold: false },
-                FormatTag { start:11, end:usize::MAX, color: TerminalColor::Default, bold: false },
-            ]
+            screen.line(1).as_deref(),
+            Some("Failed to run /nonexistent")
         );
     }
 
+    #[cfg(target_os = "linux")]
     #[test]
-    fn test_range_overlap() {
-        assert!(ranges_overlap(5..10, 7..9));
-        assert!(ranges_overlap(5..10, 8..12));
-        assert!(ranges_overlap(5..10, 3..6));
-        assert!(ranges_overlap(5..10, 2..12));
-        assert!(!ranges_overlap(5..10, 10..12));
-        assert!(!ranges_overlap(5..10, 0..5));
+    fn test_spawn_signals() {
+        use nix::libc;
+
+        // The child is forked from this thread, so it would inherit the block
+        let mut blocked = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+        let blocked = unsafe {
+            libc::sigemptyset(blocked.as_mut_ptr());
+            libc::sigaddset(blocked.as_mut_ptr(), libc::SIGUSR1);
+            blocked.assume_init()
+        };
+        let set_mask = |how| {
+            let ret = unsafe { libc::pthread_sigmask(how, &blocked, std::ptr::null_mut()) };
+            assert_eq!(ret, 0);
+        };
+
+        let config = SessionConfig {
+            program: "grep".into(),
+            args: vec![
+                "-E".into(),
+                "^Sig(Blk|Ign)".into(),
+                "/proc/self/status".into(),
+            ],
+            ..SessionConfig::default()
+        };
+        set_mask(libc::SIG_BLOCK);
+        let pty = Pty::spawn(DEFAULT_WIDTH, DEFAULT_HEIGHT, "xterm", &config);
+        set_mask(libc::SIG_UNBLOCK);
+        let mut emulator = TerminalEmulator::with_pty(pty.unwrap());
+
+        read_until(&mut emulator, TerminalEmulator::hung_up);
+        let mask = |y| {
+            let line = emulator.core().screen().line(y).unwrap();
+            let (_, mask) = line.split_once(char::is_whitespace).unwrap();
+            u64::from_str_radix(mask.trim(), 16).unwrap()
+        };
+        assert_eq!(mask(0), 0, "signals blocked");
+        assert_eq!(mask(1) & (1 << (libc::SIGPIPE - 1)), 0, "SIGPIPE ignored");
+    }
+
+    /// Output shaped like a colored ls or compiler log, plain text with the odd SGR and newline
+    fn sample_output(size: usize) -> Vec<u8> {
+        let line = "\x1b[1m\x1b[34msrc/terminal_emulator\x1b[0m: warning: unused variable `x` at \
+                    line 42\r\n\x1b[32mCompiling\x1b[0m cmlterm v0.1.0 ünïcödé ─ │\r\n";
+        line.bytes().cycle().take(size).collect()
+    }
+
+    fn print_throughput(name: &str, bytes: usize, elapsed: Duration) {
+        let mb_per_sec = bytes as f64 / 1_000_000.0 / elapsed.as_secs_f64();
+        println!("{name}: {mb_per_sec:.1} MB/s");
+    }
+
+    // Throughput benchmarks, run with
+    // cargo test --release -- --ignored --nocapture bench_
+    #[test]
+    #[ignore]
+    fn bench_parser_throughput() {
+        let data = sample_output(64 * 1024 * 1024);
+        let mut parser = AnsiParser::new();
+
+        let start = Instant::now();
+        for chunk in data.chunks(READ_BUF_SIZE) {
+            std::hint::black_box(parser.push(chunk));
+        }
+        print_throughput("AnsiParser::push", data.len(), start.elapsed());
+
+        struct CountingPerformer(usize);
+        impl Perform for CountingPerformer {
+            fn print(&mut self, data: &[u8]) {
+                self.0 += data.len();
+            }
+            fn execute(&mut self, _byte: u8) {
+                self.0 += 1;
+            }
+            fn csi_dispatch(&mut self, _: &Csi, _: bool) {
+                self.0 += 1;
+            }
+            fn esc_dispatch(&mut self, _: &[u8], _: u8) {
+                self.0 += 1;
+            }
+            fn osc_dispatch(&mut self, _: &[u8]) {
+                self.0 += 1;
+            }
+        }
+
+        let mut performer = CountingPerformer(0);
+        let start = Instant::now();
+        for chunk in data.chunks(READ_BUF_SIZE) {
+            parser.advance(chunk, &mut performer);
+        }
+        std::hint::black_box(performer.0);
+        print_throughput("AnsiParser::advance", data.len(), start.elapsed());
+    }
+
+    #[test]
+    #[ignore]
+    fn bench_emulator_throughput() {
+        let data = sample_output(64 * 1024 * 1024);
+        let mut emulator = EmulatorCore::new(DEFAULT_WIDTH, DEFAULT_HEIGHT);
+
+        let start = Instant::now();
+        for chunk in data.chunks(READ_BUF_SIZE) {
+            emulator.feed(chunk);
+        }
+        print_throughput("EmulatorCore::feed", data.len(), start.elapsed());
     }
 }
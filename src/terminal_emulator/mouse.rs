@@ -0,0 +1,258 @@
+/// Which mouse events get reported to the application, set by modes 9, 1000, 1002 and 1003
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MouseTracking {
+    None,
+    X10,
+    Normal,
+    ButtonEvent,
+    AnyEvent,
+}
+
+/// How reported mouse events are encoded, set by modes 1005, 1006 and 1015
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MouseEncoding {
+    Default,
+    Utf8,
+    Sgr,
+    Urxvt,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MouseButton {
+    Left,
+    Middle,
+    Right,
+    WheelUp,
+    WheelDown,
+}
+
+impl MouseButton {
+    fn code(&self) -> u32 {
+        match self {
+            MouseButton::Left => 0,
+            MouseButton::Middle => 1,
+            MouseButton::Right => 2,
+            MouseButton::WheelUp => 64,
+            MouseButton::WheelDown => 65,
+        }
+    }
+
+    fn is_wheel(&self) -> bool {
+        matches!(self, MouseButton::WheelUp | MouseButton::WheelDown)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MouseEventKind {
+    Press(MouseButton),
+    Release(MouseButton),
+    /// Pointer moved to a new cell, with the button that is currently held if any
+    Motion(Option<MouseButton>),
+}
+
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct MouseModifiers {
+    pub shift: bool,
+    pub alt: bool,
+    pub ctrl: bool,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct MouseEvent {
+    pub kind: MouseEventKind,
+    /// 0 indexed cell column
+    pub x: usize,
+    /// 0 indexed cell row
+    pub y: usize,
+    pub modifiers: MouseModifiers,
+}
+
+fn should_report(tracking: MouseTracking, kind: &MouseEventKind) -> bool {
+    match (tracking, kind) {
+        (MouseTracking::None, _) => false,
+        (MouseTracking::X10, MouseEventKind::Press(_)) => true,
+        (MouseTracking::X10, _) => false,
+        (MouseTracking::Normal, MouseEventKind::Motion(_)) => false,
+        (MouseTracking::ButtonEvent, MouseEventKind::Motion(button)) => button.is_some(),
+        _ => true,
+    }
+}
+
+fn button_code(tracking: MouseTracking, encoding: MouseEncoding, event: &MouseEvent) -> u32 {
+    let mut code = match event.kind {
+        MouseEventKind::Press(button) => button.code(),
+        // Only SGR can say which button was released
+        MouseEventKind::Release(button) if encoding == MouseEncoding::Sgr => button.code(),
+        MouseEventKind::Release(_) => 3,
+        MouseEventKind::Motion(Some(button)) => button.code() + 32,
+        MouseEventKind::Motion(None) => 3 + 32,
+    };
+
+    // X10 mode predates modifier reporting
+    if tracking != MouseTracking::X10 {
+        if event.modifiers.shift {
+            code += 4;
+        }
+        if event.modifiers.alt {
+            code += 8;
+        }
+        if event.modifiers.ctrl {
+            code += 16;
+        }
+    }
+
+    code
+}
+
+/// Appends a value for the legacy encodings, which offset everything by 32 to keep it printable.
+/// Returns false if the value is too large to be encoded
+fn push_legacy_value(val: u32, utf8: bool, buf: &mut Vec<u8>) -> bool {
+    let val = val + 32;
+    if !utf8 {
+        let Ok(val) = u8::try_from(val) else {
+            return false;
+        };
+        buf.push(val);
+        return true;
+    }
+
+    // 1005 only extends the coordinate range up to 2015
+    let Some(c) = char::from_u32(val).filter(|_| val < 2048) else {
+        return false;
+    };
+    let mut char_buf = [0; 4];
+    buf.extend_from_slice(c.encode_utf8(&mut char_buf).as_bytes());
+    true
+}
+
+/// Encodes the event as it should be sent to the application, or None if the current modes
+/// say it should not be reported
+pub fn encode_mouse_event(
+    tracking: MouseTracking,
+    encoding: MouseEncoding,
+    event: &MouseEvent,
+) -> Option<Vec<u8>> {
+    if !should_report(tracking, &event.kind) {
+        return None;
+    }
+
+    if let MouseEventKind::Release(button) = event.kind {
+        if button.is_wheel() {
+            return None;
+        }
+    }
+
+    let code = button_code(tracking, encoding, event);
+    let x = event.x as u32 + 1;
+    let y = event.y as u32 + 1;
+
+    match encoding {
+        MouseEncoding::Sgr => {
+            let terminator = if matches!(event.kind, MouseEventKind::Release(_)) {
+                'm'
+            } else {
+                'M'
+            };
+            Some(format!("\x1b[<{code};{x};{y}{terminator}").into_bytes())
+        }
+        MouseEncoding::Urxvt => Some(format!("\x1b[{};{x};{y}M", code + 32).into_bytes()),
+        MouseEncoding::Default | MouseEncoding::Utf8 => {
+            let utf8 = encoding == MouseEncoding::Utf8;
+            let mut ret = b"\x1b[M".to_vec();
+            // The button byte is never utf8 encoded
+            if !push_legacy_value(code, false, &mut ret)
+                || !push_legacy_value(x, utf8, &mut ret)
+                || !push_legacy_value(y, utf8, &mut ret)
+            {
+                return None;
+            }
+            Some(ret)
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn event(kind: MouseEventKind, x: usize, y: usize) -> MouseEvent {
+        MouseEvent {
+            kind,
+            x,
+            y,
+            modifiers: MouseModifiers::default(),
+        }
+    }
+
+    #[test]
+    fn test_tracking_filter() {
+        let press = event(MouseEventKind::Press(MouseButton::Left), 0, 0);
+        let release = event(MouseEventKind::Release(MouseButton::Left), 0, 0);
+        let drag = event(MouseEventKind::Motion(Some(MouseButton::Left)), 0, 0);
+        let motion = event(MouseEventKind::Motion(None), 0, 0);
+
+        let reported = |tracking| {
+            [press, release, drag, motion]
+                .iter()
+                .map(|e| encode_mouse_event(tracking, MouseEncoding::Sgr, e).is_some())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(reported(MouseTracking::None), [false, false, false, false]);
+        assert_eq!(reported(MouseTracking::X10), [true, false, false, false]);
+        assert_eq!(reported(MouseTracking::Normal), [true, true, false, false]);
+        assert_eq!(reported(MouseTracking::ButtonEvent), [true, true, true, false]);
+        assert_eq!(reported(MouseTracking::AnyEvent), [true, true, true, true]);
+    }
+
+    #[test]
+    fn test_sgr_encoding() {
+        let mut press = event(MouseEventKind::Press(MouseButton::Right), 4, 9);
+        press.modifiers.ctrl = true;
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Sgr, &press).unwrap(),
+            b"\x1b[<18;5;10M"
+        );
+
+        let release = event(MouseEventKind::Release(MouseButton::Right), 4, 9);
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Sgr, &release).unwrap(),
+            b"\x1b[<2;5;10m"
+        );
+
+        let wheel = event(MouseEventKind::Press(MouseButton::WheelDown), 0, 0);
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Sgr, &wheel).unwrap(),
+            b"\x1b[<65;1;1M"
+        );
+
+        let drag = event(MouseEventKind::Motion(Some(MouseButton::Left)), 1, 1);
+        assert_eq!(
+            encode_mouse_event(MouseTracking::ButtonEvent, MouseEncoding::Sgr, &drag).unwrap(),
+            b"\x1b[<32;2;2M"
+        );
+    }
+
+    #[test]
+    fn test_legacy_encodings() {
+        let release = event(MouseEventKind::Release(MouseButton::Left), 0, 1);
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Default, &release).unwrap(),
+            b"\x1b[M#!\""
+        );
+
+        let far = event(MouseEventKind::Press(MouseButton::Left), 300, 0);
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Default, &far),
+            None
+        );
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Utf8, &far).unwrap(),
+            "\x1b[M \u{14d}!".as_bytes()
+        );
+        assert_eq!(
+            encode_mouse_event(MouseTracking::Normal, MouseEncoding::Urxvt, &far).unwrap(),
+            b"\x1b[32;301;1M"
+        );
+    }
+}
@@ -0,0 +1,272 @@
+use super::TerminalColor;
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Rgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
+impl Rgb {
+    pub const fn new(r: u8, g: u8, b: u8) -> Rgb {
+        Rgb { r, g, b }
+    }
+}
+
+/// Colors that are not part of the 256 color table but can still be queried and changed
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum DynamicColor {
+    Foreground,
+    Background,
+    Cursor,
+}
+
+impl DynamicColor {
+    /// OSC 10, 11 and 12 address the dynamic colors in order
+    pub fn from_osc(code: usize) -> Option<DynamicColor> {
+        let ret = match code {
+            10 => DynamicColor::Foreground,
+            11 => DynamicColor::Background,
+            12 => DynamicColor::Cursor,
+            _ => return None,
+        };
+
+        Some(ret)
+    }
+
+    pub fn osc_code(&self) -> usize {
+        match self {
+            DynamicColor::Foreground => 10,
+            DynamicColor::Background => 11,
+            DynamicColor::Cursor => 12,
+        }
+    }
+}
+
+const DEFAULT_FOREGROUND: Rgb = Rgb::new(140, 140, 140);
+const DEFAULT_BACKGROUND: Rgb = Rgb::new(27, 27, 27);
+const DEFAULT_CURSOR: Rgb = Rgb::new(160, 160, 160);
+
+const DEFAULT_ANSI_COLORS: [Rgb; 16] = [
+    Rgb::new(0, 0, 0),
+    Rgb::new(255, 0, 0),
+    Rgb::new(0, 255, 0),
+    Rgb::new(255, 255, 0),
+    Rgb::new(0, 0, 255),
+    Rgb::new(255, 0, 255),
+    Rgb::new(0, 255, 255),
+    Rgb::new(255, 255, 255),
+    Rgb::new(127, 127, 127),
+    Rgb::new(255, 0, 0),
+    Rgb::new(0, 255, 0),
+    Rgb::new(255, 255, 0),
+    Rgb::new(92, 92, 255),
+    Rgb::new(255, 0, 255),
+    Rgb::new(0, 255, 255),
+    Rgb::new(255, 255, 255),
+];
+
+fn default_palette_color(index: usize) -> Rgb {
+    if index < 16 {
+        return DEFAULT_ANSI_COLORS[index];
+    }
+
+    if index < 232 {
+        // 6x6x6 color cube, matches xterm's 256colres.pl
+        let cube_val = |v: usize| if v == 0 { 0 } else { (v * 40 + 55) as u8 };
+        let index = index - 16;
+        return Rgb::new(
+            cube_val(index / 36),
+            cube_val((index / 6) % 6),
+            cube_val(index % 6),
+        );
+    }
+
+    let gray = ((index - 232) * 10 + 8) as u8;
+    Rgb::new(gray, gray, gray)
+}
+
+#[derive(Clone, Debug)]
+pub struct Palette {
+    pub colors: [Rgb; 256],
+    pub foreground: Rgb,
+    pub background: Rgb,
+    pub cursor: Rgb,
+}
+
+impl Default for Palette {
+    fn default() -> Palette {
+        Palette::new()
+    }
+}
+
+impl Palette {
+    pub fn new() -> Palette {
+        Palette {
+            colors: std::array::from_fn(default_palette_color),
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            cursor: DEFAULT_CURSOR,
+        }
+    }
+
+    pub fn resolve(&self, color: TerminalColor) -> Rgb {
+        let index = match color {
+            TerminalColor::Default => return self.foreground,
+            TerminalColor::Black => 0,
+            TerminalColor::Red => 1,
+            TerminalColor::Green => 2,
+            TerminalColor::Yellow => 3,
+            TerminalColor::Blue => 4,
+            TerminalColor::Magenta => 5,
+            TerminalColor::Cyan => 6,
+            TerminalColor::White => 7,
+        };
+
+        self.colors[index]
+    }
+
+    pub fn reset_color(&mut self, index: usize) {
+        self.colors[index] = default_palette_color(index);
+    }
+
+    pub fn reset_colors(&mut self) {
+        self.colors = std::array::from_fn(default_palette_color);
+    }
+
+    pub fn dynamic_color(&self, color: DynamicColor) -> Rgb {
+        match color {
+            DynamicColor::Foreground => self.foreground,
+            DynamicColor::Background => self.background,
+            DynamicColor::Cursor => self.cursor,
+        }
+    }
+
+    pub fn set_dynamic_color(&mut self, color: DynamicColor, val: Rgb) {
+        match color {
+            DynamicColor::Foreground => self.foreground = val,
+            DynamicColor::Background => self.background = val,
+            DynamicColor::Cursor => self.cursor = val,
+        }
+    }
+
+    pub fn reset_dynamic_color(&mut self, color: DynamicColor) {
+        let val = match color {
+            DynamicColor::Foreground => DEFAULT_FOREGROUND,
+            DynamicColor::Background => DEFAULT_BACKGROUND,
+            DynamicColor::Cursor => DEFAULT_CURSOR,
+        };
+        self.set_dynamic_color(color, val);
+    }
+}
+
+/// Scales a 1-4 digit hex color component to 8 bits
+fn parse_scaled_component(component: &[u8]) -> Option<u8> {
+    if component.is_empty() || component.len() > 4 {
+        return None;
+    }
+
+    let component = std::str::from_utf8(component).ok()?;
+    let val = u32::from_str_radix(component, 16).ok()?;
+    let max = (1u32 << (4 * component.len())) - 1;
+    Some(((val * 255 + max / 2) / max) as u8)
+}
+
+/// Parses an X11 color specification, either rgb:r/g/b or #rgb with 1-4 digits per component
+pub fn parse_color_spec(spec: &[u8]) -> Option<Rgb> {
+    if let Some(components) = spec.strip_prefix(b"rgb:") {
+        let mut it = components.split(|b| *b == b'/');
+        let r = parse_scaled_component(it.next()?)?;
+        let g = parse_scaled_component(it.next()?)?;
+        let b = parse_scaled_component(it.next()?)?;
+        if it.next().is_some() {
+            return None;
+        }
+        return Some(Rgb::new(r, g, b));
+    }
+
+    if let Some(digits) = spec.strip_prefix(b"#") {
+        if digits.is_empty() || !digits.len().is_multiple_of(3) || digits.len() > 12 {
+            return None;
+        }
+
+        // Unlike rgb:, the # form takes the most significant bits rather than scaling
+        let component_len = digits.len() / 3;
+        let parse_component = |idx: usize| -> Option<u8> {
+            let component = &digits[idx * component_len..(idx + 1) * component_len];
+            let component = std::str::from_utf8(component).ok()?;
+            let val = u32::from_str_radix(component, 16).ok()?;
+            let bits = 4 * component_len as u32;
+            if bits >= 8 {
+                Some((val >> (bits - 8)) as u8)
+            } else {
+                Some((val << (8 - bits)) as u8)
+            }
+        };
+
+        return Some(Rgb::new(
+            parse_component(0)?,
+            parse_component(1)?,
+            parse_component(2)?,
+        ));
+    }
+
+    None
+}
+
+/// Formats a color the way xterm reports it in OSC responses
+pub fn format_color_spec(color: Rgb) -> String {
+    let scale = |v: u8| v as u32 * 257;
+    format!(
+        "rgb:{:04x}/{:04x}/{:04x}",
+        scale(color.r),
+        scale(color.g),
+        scale(color.b)
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_default_palette() {
+        let palette = Palette::new();
+        assert_eq!(palette.colors[1], Rgb::new(255, 0, 0));
+        assert_eq!(palette.colors[16], Rgb::new(0, 0, 0));
+        assert_eq!(palette.colors[21], Rgb::new(0, 0, 255));
+        assert_eq!(palette.colors[196], Rgb::new(255, 0, 0));
+        assert_eq!(palette.colors[231], Rgb::new(255, 255, 255));
+        assert_eq!(palette.colors[232], Rgb::new(8, 8, 8));
+        assert_eq!(palette.colors[255], Rgb::new(238, 238, 238));
+    }
+
+    #[test]
+    fn test_parse_color_spec() {
+        assert_eq!(parse_color_spec(b"rgb:ff/00/80"), Some(Rgb::new(255, 0, 128)));
+        assert_eq!(parse_color_spec(b"rgb:f/0/8"), Some(Rgb::new(255, 0, 136)));
+        assert_eq!(
+            parse_color_spec(b"rgb:ffff/0000/8080"),
+            Some(Rgb::new(255, 0, 128))
+        );
+        assert_eq!(parse_color_spec(b"#ff0080"), Some(Rgb::new(255, 0, 128)));
+        assert_eq!(parse_color_spec(b"#f08"), Some(Rgb::new(240, 0, 128)));
+        assert_eq!(
+            parse_color_spec(b"#ffff00008080"),
+            Some(Rgb::new(255, 0, 128))
+        );
+        assert_eq!(parse_color_spec(b"rgb:ff/00"), None);
+        assert_eq!(parse_color_spec(b"rgb:ff/00/80/00"), None);
+        assert_eq!(parse_color_spec(b"rgb:fffff/00/80"), None);
+        assert_eq!(parse_color_spec(b"#ff00"), None);
+        assert_eq!(parse_color_spec(b"red"), None);
+    }
+
+    #[test]
+    fn test_format_color_spec() {
+        assert_eq!(
+            format_color_spec(Rgb::new(255, 0, 128)),
+            "rgb:ffff/0000/8080"
+        );
+    }
+}
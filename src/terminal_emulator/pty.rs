@@ -0,0 +1,273 @@
+use std::{
+    ffi::CString,
+    os::fd::{AsRawFd, OwnedFd},
+};
+
+use nix::{
+    errno::Errno,
+    libc,
+    pty::Winsize,
+    sys::{
+        termios::{cfmakeraw, tcgetattr, tcsetattr, SetArg},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
+    unistd::{ForkResult, Pid},
+};
+
+use super::{ChildExitStatus, SessionConfig, TerminalError};
+
+fn winsize(width: usize, height: usize) -> Winsize {
+    Winsize {
+        ws_row: height as u16,
+        ws_col: width as u16,
+        ws_xpixel: 0,
+        ws_ypixel: 0,
+    }
+}
+
+/// Spawn the configured program in a child process and return the file descriptor used for I/O
+/// along with the child's pid
+fn spawn_shell(
+    width: usize,
+    height: usize,
+    term: &str,
+    config: &SessionConfig,
+) -> Result<(OwnedFd, Pid), TerminalError> {
+    let winsize = winsize(width, height);
+
+    // Allocate before forking, the child of a multithreaded process may only make async signal
+    // safe calls, so all it does is chdir and exec
+    let environment = config.environment(term);
+    let program = config.program_path(&environment);
+    let argv = config.argv();
+    let envp = SessionConfig::envp(&environment);
+    let argv_ptrs = exec_array(&argv);
+    let envp_ptrs = exec_array(&envp);
+    let cwd = config.cwd_cstring();
+    let cwd_error = config.cwd.as_ref().map_or_else(String::new, |cwd| {
+        format!("Failed to change directory to {}\n", cwd.display())
+    });
+    let exec_error = format!("Failed to run {}\n", config.program.display());
+
+    unsafe {
+        let res = nix::pty::forkpty(Some(&winsize), None).map_err(TerminalError::Spawn)?;
+        let child = match res.fork_result {
+            ForkResult::Parent { child } => child,
+            ForkResult::Child => {
+                reset_child_signals();
+
+                // Errors from here on are written to the pty, where the user sees them
+                if let Some(cwd) = &cwd {
+                    if libc::chdir(cwd.as_ptr()) != 0 {
+                        child_write(&cwd_error);
+                    }
+                }
+
+                libc::execve(program.as_ptr(), argv_ptrs.as_ptr(), envp_ptrs.as_ptr());
+                // Should only run if exec failed
+                child_write(&exec_error);
+                libc::_exit(1);
+            }
+        };
+        Ok((res.master, child))
+    }
+}
+
+/// Puts back the signal state a new program expects. Ignored signals and the signal mask survive
+/// execve, and Rust ignores SIGPIPE at startup, which the shell and everything it runs would
+/// otherwise inherit. Only makes async signal safe calls
+fn reset_child_signals() {
+    unsafe {
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+
+        let mut mask = std::mem::MaybeUninit::<libc::sigset_t>::uninit();
+        libc::sigemptyset(mask.as_mut_ptr());
+        libc::sigprocmask(libc::SIG_SETMASK, mask.as_ptr(), std::ptr::null_mut());
+    }
+}
+
+/// Null terminated array of pointers into strings, for execve
+fn exec_array(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|s| s.as_ptr())
+        .chain(std::iter::once(std::ptr::null()))
+        .collect()
+}
+
+/// Writes a message to the child's stdout, which is the pty, without allocating
+fn child_write(message: &str) {
+    unsafe {
+        libc::write(libc::STDOUT_FILENO, message.as_ptr().cast(), message.len());
+    }
+}
+
+fn set_nonblock(fd: &OwnedFd) -> Result<(), TerminalError> {
+    let flags = nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_GETFL)
+        .map_err(TerminalError::PtySetup)?;
+    let mut flags =
+        nix::fcntl::OFlag::from_bits_truncate(flags & nix::fcntl::OFlag::O_ACCMODE.bits());
+    flags.set(nix::fcntl::OFlag::O_NONBLOCK, true);
+
+    nix::fcntl::fcntl(fd.as_raw_fd(), nix::fcntl::FcntlArg::F_SETFL(flags))
+        .map_err(TerminalError::PtySetup)?;
+    Ok(())
+}
+
+/// The pty the child runs on, which is all the emulator knows of it
+pub struct Pty {
+    fd: OwnedFd,
+    /// None for a pty opened without a child, see [`Pty::pair`]
+    child: Option<Pid>,
+    /// Set once reading says the other end of the pty is gone
+    hung_up: bool,
+    exit_status: Option<ChildExitStatus>,
+    /// Input the child hasn't taken yet, sent once the pty has room for it again
+    unwritten: Vec<u8>,
+}
+
+impl Pty {
+    /// Starts the configured program on a new pty
+    pub fn spawn(
+        width: usize,
+        height: usize,
+        term: &str,
+        config: &SessionConfig,
+    ) -> Result<Pty, TerminalError> {
+        let (fd, child) = spawn_shell(width, height, term, config)?;
+        set_nonblock(&fd)?;
+
+        Ok(Pty {
+            fd,
+            child: Some(child),
+            hung_up: false,
+            exit_status: None,
+            unwritten: Vec::new(),
+        })
+    }
+
+    /// Opens a pty without starting anything on it, returning the end the application would
+    /// use along with it. That end is in raw mode, so whatever is written to it reaches the
+    /// emulator unchanged. Lets tests stand in for the application
+    pub fn pair(width: usize, height: usize) -> Result<(Pty, OwnedFd), TerminalError> {
+        let pty =
+            nix::pty::openpty(Some(&winsize(width, height)), None).map_err(TerminalError::Spawn)?;
+
+        let mut termios = tcgetattr(&pty.slave).map_err(TerminalError::PtySetup)?;
+        cfmakeraw(&mut termios);
+        tcsetattr(&pty.slave, SetArg::TCSANOW, &termios).map_err(TerminalError::PtySetup)?;
+        set_nonblock(&pty.master)?;
+
+        let pty_end = Pty {
+            fd: pty.master,
+            child: None,
+            hung_up: false,
+            exit_status: None,
+            unwritten: Vec::new(),
+        };
+        Ok((pty_end, pty.slave))
+    }
+
+    /// Copy of the fd for polling from another thread
+    pub fn try_clone_fd(&self) -> std::io::Result<OwnedFd> {
+        self.fd.try_clone()
+    }
+
+    /// Reads whatever output is waiting into buf. None if there is nothing, or once the other
+    /// end has hung up
+    pub fn read(&mut self, buf: &mut [u8]) -> Option<usize> {
+        if self.hung_up {
+            return None;
+        }
+
+        match nix::unistd::read(self.fd.as_raw_fd(), buf) {
+            // Linux reports a closed pty as EIO rather than end of file
+            Ok(0) | Err(Errno::EIO) => {
+                self.hung_up = true;
+                None
+            }
+            Ok(read_size) => Some(read_size),
+            Err(Errno::EAGAIN) => None,
+            Err(e) => {
+                log::warn!(target: "pty", "Failed to read: {e}");
+                None
+            }
+        }
+    }
+
+    /// Sends input to the child. Once it has hung up there is nobody to send to, so the input is
+    /// dropped. Whatever doesn't fit in the pty is queued rather than waited on, the caller is
+    /// usually holding the emulator lock. See [`Pty::flush`]
+    pub fn write(&mut self, to_write: &[u8]) -> Result<(), TerminalError> {
+        if self.hung_up {
+            return Ok(());
+        }
+
+        self.unwritten.extend_from_slice(to_write);
+        self.flush()
+    }
+
+    /// Sends as much of the queued input as the pty has room for
+    pub fn flush(&mut self) -> Result<(), TerminalError> {
+        if self.hung_up {
+            self.unwritten.clear();
+            return Ok(());
+        }
+
+        while !self.unwritten.is_empty() {
+            match nix::unistd::write(self.fd.as_raw_fd(), &self.unwritten) {
+                Ok(written) => {
+                    self.unwritten.drain(..written);
+                }
+                Err(Errno::EINTR) => {}
+                Err(Errno::EAGAIN) => break,
+                Err(e) => {
+                    self.unwritten.clear();
+                    return Err(TerminalError::Write(e));
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Whether some input is still waiting for room in the pty
+    pub fn has_unwritten(&self) -> bool {
+        !self.unwritten.is_empty()
+    }
+
+    /// Tells the application about a new screen size
+    pub fn set_win_size(&mut self, width: usize, height: usize) {
+        let winsize = winsize(width, height);
+        let ret = unsafe { nix::libc::ioctl(self.fd.as_raw_fd(), nix::libc::TIOCSWINSZ, &winsize) };
+        if ret != 0 {
+            log::warn!(target: "pty", "Failed to set window size: {}", Errno::last());
+        }
+    }
+
+    /// Whether the other end of the pty has been closed, after which nothing more can be read
+    pub fn hung_up(&self) -> bool {
+        self.hung_up
+    }
+
+    /// Reaps the child if it has exited, returning its status the first time
+    pub fn try_reap_child(&mut self) -> Option<ChildExitStatus> {
+        let child = self.child?;
+        if self.exit_status.is_some() {
+            return None;
+        }
+
+        let status = match waitpid(child, Some(WaitPidFlag::WNOHANG)) {
+            Ok(WaitStatus::Exited(_, code)) => ChildExitStatus::Exited(code),
+            Ok(WaitStatus::Signaled(_, signal, _)) => ChildExitStatus::Signaled(signal as i32),
+            Ok(_) => return None,
+            Err(e) => {
+                log::warn!(target: "pty", "Failed to wait for child: {e}");
+                return None;
+            }
+        };
+
+        self.exit_status = Some(status);
+        Some(status)
+    }
+}
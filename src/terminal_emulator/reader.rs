@@ -0,0 +1,93 @@
+use std::{
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags},
+};
+
+use super::{TerminalEmulator, TerminalError, TerminalEvent};
+
+/// How often to check on the child while the pty is quiet, it can exit while something else still
+/// holds the pty open
+const CHILD_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Processes pty output on a background thread as soon as it arrives, rather than whenever the GUI
+/// happens to draw. wake is called whenever something changed so the GUI can repaint
+pub fn spawn_reader_thread<F>(
+    emulator: Arc<Mutex<TerminalEmulator>>,
+    wake: F,
+) -> Result<Receiver<TerminalEvent>, TerminalError>
+where
+    F: Fn() + Send + 'static,
+{
+    // Polling on our own copy of the fd means the lock is only held while there is work to do
+    let fd = emulator
+        .lock()
+        .unwrap()
+        .pty
+        .try_clone_fd()
+        .map_err(TerminalError::ReaderThread)?;
+    let (tx, rx) = mpsc::channel();
+
+    std::thread::Builder::new()
+        .name("pty-reader".to_string())
+        .spawn(move || {
+            let mut exited = false;
+            let mut unwritten = false;
+            loop {
+                // Input that didn't fit in the pty goes out once the child has made room for it
+                let mut flags = PollFlags::POLLIN;
+                if unwritten {
+                    flags |= PollFlags::POLLOUT;
+                }
+                let mut fds = [PollFd::new(&fd, flags)];
+                let readable = match poll(&mut fds, CHILD_CHECK_INTERVAL.as_millis() as i32) {
+                    // Room to write alone doesn't change anything on screen
+                    Ok(_) => fds[0]
+                        .revents()
+                        .is_some_and(|revents| !revents.difference(PollFlags::POLLOUT).is_empty()),
+                    Err(Errno::EINTR) => continue,
+                    Err(e) => {
+                        log::error!(target: "pty", "Failed to poll pty: {e}");
+                        return;
+                    }
+                };
+
+                let (event, hung_up) = {
+                    let mut emulator = emulator.lock().unwrap();
+                    let event = emulator.read();
+                    unwritten = emulator.has_unwritten();
+                    (event, emulator.hung_up())
+                };
+
+                if readable || event.is_some() {
+                    wake();
+                }
+
+                if let Some(event) = event {
+                    exited = true;
+                    if tx.send(event).is_err() {
+                        // The GUI is gone
+                        return;
+                    }
+                }
+
+                if hung_up {
+                    if exited {
+                        return;
+                    }
+                    // A hung up pty is always readable, don't spin while waiting for the child
+                    std::thread::sleep(CHILD_CHECK_INTERVAL);
+                }
+            }
+        })
+        .map_err(TerminalError::ReaderThread)?;
+
+    Ok(rx)
+}
@@ -0,0 +1,209 @@
+use std::{
+    ffi::{CString, OsStr, OsString},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+};
+
+use nix::unistd::AccessFlags;
+
+/// Used if neither $SHELL nor the passwd entry name a shell
+const FALLBACK_SHELL: &str = "/bin/sh";
+
+/// Searched for the program if the environment has no PATH, like execvp does
+const DEFAULT_PATH: &str = "/usr/local/bin:/usr/bin:/bin";
+
+/// The user's login shell, from $SHELL or the passwd entry
+fn default_shell() -> PathBuf {
+    if let Some(shell) = std::env::var_os("SHELL").filter(|shell| !shell.is_empty()) {
+        return shell.into();
+    }
+
+    match nix::unistd::User::from_uid(nix::unistd::getuid()) {
+        Ok(Some(user)) if !user.shell.as_os_str().is_empty() => user.shell,
+        _ => FALLBACK_SHELL.into(),
+    }
+}
+
+fn to_cstring(s: &OsStr) -> CString {
+    // Arguments cannot contain nul bytes, cut them off there like a C caller would
+    let bytes = s.as_bytes();
+    let len = bytes.iter().position(|b| *b == 0).unwrap_or(bytes.len());
+    CString::new(&bytes[..len]).expect("nul bytes were removed")
+}
+
+fn is_executable(path: &Path) -> bool {
+    path.is_file() && nix::unistd::access(path, AccessFlags::X_OK).is_ok()
+}
+
+/// What to run in the terminal and the environment to run it in
+#[derive(Clone, Debug)]
+pub struct SessionConfig {
+    /// Program to run, looked up in PATH if it is not a path
+    pub program: PathBuf,
+    /// Arguments passed after argv[0]
+    pub args: Vec<String>,
+    /// Variables set in the child's environment, applied after env_remove
+    pub env: Vec<(String, String)>,
+    /// Variables removed from the inherited environment
+    pub env_remove: Vec<String>,
+    /// Directory to start in, defaults to the current one
+    pub cwd: Option<PathBuf>,
+    /// Start the program as a login shell by prefixing argv[0] with a dash
+    pub login: bool,
+}
+
+impl Default for SessionConfig {
+    fn default() -> SessionConfig {
+        SessionConfig {
+            program: default_shell(),
+            args: Vec::new(),
+            env: Vec::new(),
+            env_remove: Vec::new(),
+            cwd: None,
+            login: false,
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Program to exec, a bare name is looked up in the PATH of the child's environment like
+    /// execvp would. Left as is if it isn't found, so that exec fails on it
+    pub fn program_path(&self, environment: &[(OsString, OsString)]) -> CString {
+        let program = self.program.as_os_str();
+        if program.as_bytes().contains(&b'/') {
+            return to_cstring(program);
+        }
+
+        let path = environment
+            .iter()
+            .find(|(name, _)| name == "PATH")
+            .map_or(OsStr::new(DEFAULT_PATH), |(_, val)| val.as_os_str());
+        std::env::split_paths(path)
+            .map(|dir| dir.join(program))
+            .find(|candidate| is_executable(candidate))
+            .map_or_else(
+                || to_cstring(program),
+                |found| to_cstring(found.as_os_str()),
+            )
+    }
+
+    /// The child's environment: the inherited one with the terminal's own variables, then
+    /// env_remove taken out and env set on top
+    pub fn environment(&self, term: &str) -> Vec<(OsString, OsString)> {
+        let mut vars = std::env::vars_os().collect::<Vec<_>>();
+        let mut set = |name: &str, val: &str| {
+            vars.retain(|(existing, _)| existing != name);
+            vars.push((name.into(), val.into()));
+        };
+
+        set("TERM", term);
+        set("TERM_PROGRAM", env!("CARGO_PKG_NAME"));
+        set("TERM_PROGRAM_VERSION", env!("CARGO_PKG_VERSION"));
+        // Only 8 colors are supported, whatever terminal we were started from may claim more
+        vars.retain(|(name, _)| name != "COLORTERM");
+        vars.retain(|(name, _)| !self.env_remove.iter().any(|remove| name == remove.as_str()));
+        for (name, val) in &self.env {
+            vars.retain(|(existing, _)| existing != name.as_str());
+            vars.push((name.into(), val.into()));
+        }
+        vars
+    }
+
+    /// Environment in the NAME=value form execve takes
+    pub fn envp(environment: &[(OsString, OsString)]) -> Vec<CString> {
+        environment
+            .iter()
+            .map(|(name, val)| {
+                let mut entry = name.clone();
+                entry.push("=");
+                entry.push(val);
+                to_cstring(&entry)
+            })
+            .collect()
+    }
+
+    pub fn cwd_cstring(&self) -> Option<CString> {
+        self.cwd.as_deref().map(|cwd| to_cstring(cwd.as_os_str()))
+    }
+
+    /// Full argv for exec, including argv[0]
+    pub fn argv(&self) -> Vec<CString> {
+        let name = self
+            .program
+            .file_name()
+            .unwrap_or(self.program.as_os_str())
+            .as_bytes();
+
+        let mut argv0 = Vec::new();
+        if self.login {
+            argv0.push(b'-');
+        }
+        argv0.extend_from_slice(name);
+
+        std::iter::once(to_cstring(OsStr::from_bytes(&argv0)))
+            .chain(self.args.iter().map(|arg| to_cstring(arg.as_ref())))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_argv() {
+        let mut config = SessionConfig {
+            program: "/usr/bin/zsh".into(),
+            args: vec!["-c".to_string(), "echo hi".to_string()],
+            ..SessionConfig::default()
+        };
+        assert_eq!(config.program_path(&[]).as_bytes(), b"/usr/bin/zsh");
+        assert_eq!(
+            config.argv(),
+            [c"zsh".to_owned(), c"-c".to_owned(), c"echo hi".to_owned()]
+        );
+
+        config.login = true;
+        config.args = vec!["a\0b".to_string()];
+        assert_eq!(config.argv(), [c"-zsh".to_owned(), c"a".to_owned()]);
+    }
+
+    #[test]
+    fn test_program_path() {
+        let config = SessionConfig {
+            program: "sh".into(),
+            ..SessionConfig::default()
+        };
+        let path = [("PATH".into(), "/nonexistent:/bin".into())];
+        assert_eq!(config.program_path(&path).as_bytes(), b"/bin/sh");
+        let path = [("PATH".into(), "/nonexistent".into())];
+        assert_eq!(config.program_path(&path).as_bytes(), b"sh");
+    }
+
+    #[test]
+    fn test_environment() {
+        let config = SessionConfig {
+            env: vec![
+                ("TERM_PROGRAM".to_string(), "other".to_string()),
+                ("EDITOR".to_string(), "vim".to_string()),
+            ],
+            env_remove: vec!["TERM_PROGRAM_VERSION".to_string()],
+            ..SessionConfig::default()
+        };
+        let environment = config.environment("xterm");
+        let get = |name: &str| {
+            let mut matches = environment.iter().filter(|(key, _)| key == name);
+            let val = matches.next().map(|(_, val)| val.to_str().unwrap());
+            assert!(matches.next().is_none(), "{name} set twice");
+            val
+        };
+        assert_eq!(get("TERM"), Some("xterm"));
+        assert_eq!(get("COLORTERM"), None);
+        assert_eq!(get("TERM_PROGRAM"), Some("other"));
+        assert_eq!(get("TERM_PROGRAM_VERSION"), None);
+        assert_eq!(get("EDITOR"), Some("vim"));
+
+        let envp = SessionConfig::envp(&[("A".into(), "b=c".into())]);
+        assert_eq!(envp, [c"A=b=c".to_owned()]);
+    }
+}
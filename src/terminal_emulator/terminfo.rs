@@ -0,0 +1,109 @@
+use std::{
+    io::Write,
+    path::{Path, PathBuf},
+    process::{Command, Stdio},
+};
+
+const TERMINFO_NAME: &str = "cmlterm";
+const TERMINFO_SOURCE: &[u8] = include_bytes!("../../res/cmlterm.terminfo");
+
+/// Used when our own entry is not available, close enough for most programs. Only 8 colors are
+/// supported, so nothing that claims more
+const FALLBACK_TERM: &str = "xterm";
+
+fn user_terminfo_dir() -> Option<PathBuf> {
+    let home = std::env::var_os("HOME")?;
+    Some(Path::new(&home).join(".terminfo"))
+}
+
+/// ncurses uses the first letter as the directory name, some platforms use its hex value instead
+fn is_installed(terminfo_dir: &Path) -> bool {
+    let first = &TERMINFO_NAME[..1];
+    let hex = format!("{:x}", first.as_bytes()[0]);
+    [first, hex.as_str()]
+        .iter()
+        .any(|dir| terminfo_dir.join(dir).join(TERMINFO_NAME).exists())
+}
+
+/// Compiles the bundled source into terminfo_dir. The source goes to tic on stdin, a file in a
+/// shared temporary directory could be swapped out or replaced by a symlink before tic reads it
+fn install(terminfo_dir: &Path) -> std::io::Result<()> {
+    let mut tic = Command::new("tic")
+        .arg("-x")
+        .arg("-o")
+        .arg(terminfo_dir)
+        .arg("-")
+        .stdin(Stdio::piped())
+        .spawn()?;
+
+    let mut stdin = tic.stdin.take().expect("stdin is piped");
+    let written = stdin.write_all(TERMINFO_SOURCE);
+    // tic only starts compiling once its input is closed
+    drop(stdin);
+
+    let status = tic.wait()?;
+    written?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!("tic exited with {status}")));
+    }
+
+    Ok(())
+}
+
+/// Installs the bundled terminfo entry into ~/.terminfo if it is not there yet, and returns the
+/// value the child should get for TERM
+pub fn ensure_installed() -> &'static str {
+    let Some(terminfo_dir) = user_terminfo_dir() else {
+        return FALLBACK_TERM;
+    };
+
+    if is_installed(&terminfo_dir) {
+        return TERMINFO_NAME;
+    }
+
+    if let Err(e) = install(&terminfo_dir) {
+        log::warn!(target: "pty", "Failed to install terminfo entry: {e}");
+        return FALLBACK_TERM;
+    }
+
+    if !is_installed(&terminfo_dir) {
+        log::warn!(target: "pty", "Installed terminfo entry could not be found");
+        return FALLBACK_TERM;
+    }
+
+    TERMINFO_NAME
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_is_installed() {
+        let dir =
+            std::env::temp_dir().join(format!("cmlterm-terminfo-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        assert!(!is_installed(&dir));
+
+        std::fs::create_dir_all(dir.join("63")).unwrap();
+        std::fs::write(dir.join("63").join(TERMINFO_NAME), b"").unwrap();
+        assert!(is_installed(&dir));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_install() {
+        if Command::new("tic").arg("-V").output().is_err() {
+            // Nothing to compile the entry with
+            return;
+        }
+
+        let dir = std::env::temp_dir().join(format!("cmlterm-install-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        install(&dir).unwrap();
+        assert!(is_installed(&dir));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,186 @@
+use std::{
+    collections::BTreeMap,
+    fmt,
+    sync::{Mutex, PoisonError},
+};
+
+/// Distinct sequences counted before the rest are only added up, so a program writing random
+/// bytes can't grow the table without bound
+const MAX_KEYS: usize = 256;
+
+/// What a sequence is counted as. Made of a static kind and a small code rather than a formatted
+/// name, since output can be full of sequences we don't support
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct Key {
+    kind: &'static str,
+    code: Code,
+}
+
+#[derive(Clone, Copy, Debug, Eq, Ord, PartialEq, PartialOrd)]
+enum Code {
+    /// The parameter that selects the feature, e.g. the mode of "DEC mode 1004"
+    Number(usize),
+    /// The bytes that pick out the sequence, e.g. "?$p" for "CSI ?$p", zero padded
+    Bytes([u8; 4]),
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Code::Number(number) => write!(f, "{} {number}", self.kind),
+            Code::Bytes(bytes) => {
+                let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                let bytes = String::from_utf8_lossy(&bytes[..len]);
+                write!(f, "{} {bytes}", self.kind)
+            }
+        }
+    }
+}
+
+struct Counts {
+    counts: BTreeMap<Key, u64>,
+    /// Sequences seen after MAX_KEYS distinct ones were already counted
+    other: u64,
+}
+
+impl Counts {
+    const fn new() -> Counts {
+        Counts {
+            counts: BTreeMap::new(),
+            other: 0,
+        }
+    }
+
+    fn add(&mut self, key: Key) {
+        if let Some(count) = self.counts.get_mut(&key) {
+            *count += 1;
+        } else if self.counts.len() < MAX_KEYS {
+            self.counts.insert(key, 1);
+        } else {
+            self.other += 1;
+        }
+    }
+
+    fn summary(&self) -> Vec<(String, u64)> {
+        let mut summary = self
+            .counts
+            .iter()
+            .map(|(key, count)| (key.to_string(), *count))
+            .collect::<Vec<_>>();
+        // Sorting is stable, so equal counts stay in key order
+        summary.sort_by(|(_, a), (_, b)| b.cmp(a));
+        if self.other > 0 {
+            summary.push(("other".to_string(), self.other));
+        }
+        summary
+    }
+}
+
+/// How often each unsupported sequence has been seen, to show which missing features matter
+static UNHANDLED: Mutex<Counts> = Mutex::new(Counts::new());
+
+fn count(target: &str, key: Key) {
+    log::debug!(target: target, "Unhandled {key}");
+
+    let mut unhandled = UNHANDLED.lock().unwrap_or_else(PoisonError::into_inner);
+    unhandled.add(key);
+}
+
+/// Logs and counts a feature we don't support, selected by a parameter, e.g. kind "SGR" and code
+/// 5 for blinking text
+pub fn record(target: &str, kind: &'static str, code: usize) {
+    count(
+        target,
+        Key {
+            kind,
+            code: Code::Number(code),
+        },
+    );
+}
+
+/// Logs and counts a sequence we don't support by the bytes that identify it, e.g. kind "CSI" and
+/// "?$p". These should leave out parameters that vary, so that uses of the same feature are
+/// counted together. Only the first 4 bytes are kept
+pub fn record_bytes(target: &str, kind: &'static str, bytes: impl IntoIterator<Item = u8>) {
+    let mut code = [0; 4];
+    for (slot, b) in code.iter_mut().zip(bytes) {
+        *slot = b;
+    }
+    count(
+        target,
+        Key {
+            kind,
+            code: Code::Bytes(code),
+        },
+    );
+}
+
+/// Unsupported sequences seen so far with their counts, most frequent first. Sequences past the
+/// first MAX_KEYS distinct ones are added up under "other" at the end
+pub fn summary() -> Vec<(String, u64)> {
+    let unhandled = UNHANDLED.lock().unwrap_or_else(PoisonError::into_inner);
+    unhandled.summary()
+}
+
+/// Logs the statistics, meant for when the terminal closes. Logged as a warning so it shows up
+/// without raising the log level
+pub fn log_summary() {
+    let summary = summary();
+    if summary.is_empty() {
+        return;
+    }
+
+    let summary = summary
+        .iter()
+        .map(|(name, count)| format!("{name} x{count}"))
+        .collect::<Vec<_>>()
+        .join(", ");
+    log::warn!(target: "emulator", "Unhandled sequences seen: {summary}");
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_summary() {
+        // Other tests record too, so only look at kinds nothing else uses
+        for _ in 0..3 {
+            record_bytes("parser", "TEST", *b"?$p");
+        }
+        record("parser", "TEST", 5);
+        record_bytes("parser", "TEST", *b"abcdef");
+
+        let summary = summary()
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("TEST "))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            summary,
+            [
+                ("TEST ?$p".to_string(), 3),
+                ("TEST 5".to_string(), 1),
+                ("TEST abcd".to_string(), 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_key_cap() {
+        let key = |code| Key {
+            kind: "TEST",
+            code: Code::Number(code),
+        };
+        let mut counts = Counts::new();
+        for code in 0..MAX_KEYS + 2 {
+            counts.add(key(code));
+        }
+        // Keys that made it in keep counting
+        counts.add(key(0));
+
+        let summary = counts.summary();
+        assert_eq!(summary.len(), MAX_KEYS + 1);
+        assert_eq!(summary[0], ("TEST 0".to_string(), 2));
+        assert_eq!(summary[MAX_KEYS], ("other".to_string(), 2));
+    }
+}